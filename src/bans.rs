@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// A range of IP addresses, written in CIDR notation like `10.0.0.0/8`. A bare address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// The range of addresses that share the first `prefix` bits with the given address. Returns `None` if the prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        // IPv4-mapped ranges are IPv4 ranges, like the addresses in them
+        let (addr, prefix) = match addr {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix - 96),
+                None => (addr, prefix),
+            },
+            _ => (addr, prefix),
+        };
+        let addr = match addr {
+            IpAddr::V4(v4) if prefix <= 32 => {
+                Ipv4Addr::from(u32::from(v4) & v4_mask(prefix)).into()
+            }
+            IpAddr::V6(v6) if prefix <= 128 => {
+                Ipv6Addr::from(u128::from(v6) & v6_mask(prefix)).into()
+            }
+            _ => return None,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    /// The range holding just the given address.
+    pub fn single(addr: IpAddr) -> Self {
+        let addr = canonical(addr);
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, prefix }
+    }
+
+    /// Whether the address is in the range. IPv4-mapped IPv6 addresses, which is how listeners on `[::]` see IPv4 clients, count as the IPv4 addresses they map.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Turns IPv4-mapped IPv6 addresses into plain IPv4 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        Self::single(addr)
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = ParseIpRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseIpRangeError(s.to_owned());
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse().map_err(|_| err())?;
+                let prefix = prefix.parse().map_err(|_| err())?;
+                Self::new(addr, prefix).ok_or_else(err)
+            }
+            None => Ok(Self::single(s.parse().map_err(|_| err())?)),
+        }
+    }
+}
+
+/// An error parsing an [IpRange].
+#[derive(Error, Debug, Clone)]
+#[error("invalid IP range: `{0}`")]
+pub struct ParseIpRangeError(String);
+
+/// A ban on a range of addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    /// The banned addresses.
+    pub range: IpRange,
+    /// When the ban lifts by itself, if ever.
+    pub expires: Option<SystemTime>,
+}
+
+impl Ban {
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.expires.map(|expires| expires > now).unwrap_or(true)
+    }
+}
+
+/// The ranges of addresses we refuse to talk to.
+#[derive(Default)]
+pub(crate) struct BanList {
+    bans: RwLock<HashMap<IpRange, Ban>>,
+}
+
+impl BanList {
+    /// Bans a range, for the given duration or forever, replacing any earlier ban on exactly the same range.
+    pub fn ban(&self, range: IpRange, duration: Option<Duration>) {
+        let now = SystemTime::now();
+        // a ban too long to have an expiry time is a permanent one
+        let expires = duration.and_then(|duration| now.checked_add(duration));
+        let mut bans = self.bans.write();
+        bans.retain(|_, ban| ban.is_active(now));
+        bans.insert(range, Ban { range, expires });
+    }
+
+    /// Lifts the ban on exactly the given range, returning whether there was one.
+    pub fn unban(&self, range: &IpRange) -> bool {
+        self.bans.write().remove(range).is_some()
+    }
+
+    /// Whether any ban covers the address.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = SystemTime::now();
+        self.bans
+            .read()
+            .values()
+            .any(|ban| ban.is_active(now) && ban.range.contains(ip))
+    }
+
+    /// Lists the bans in force, forgetting the ones that expired.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = SystemTime::now();
+        let mut bans = self.bans.write();
+        bans.retain(|_, ban| ban.is_active(now));
+        bans.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range: IpRange = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(range.to_string(), "10.0.0.0/8");
+        let range: IpRange = "10.1.2.3".parse().unwrap();
+        assert_eq!(range.to_string(), "10.1.2.3/32");
+        let range: IpRange = "2001:db8::1/32".parse().unwrap();
+        assert_eq!(range.to_string(), "2001:db8::/32");
+        let range: IpRange = "::ffff:10.1.2.3/104".parse().unwrap();
+        assert_eq!(range.to_string(), "10.0.0.0/8");
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/".parse::<IpRange>().is_err());
+        assert!("nonsense".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn contains_addresses() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains(ip("10.255.0.1")));
+        assert!(!range.contains(ip("11.0.0.1")));
+        assert!(range.contains(ip("::ffff:10.0.0.1")));
+        assert!(!range.contains(ip("::a00:1")));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("1.2.3.4")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+
+        let range: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+
+        let single = IpRange::single(ip("::ffff:1.2.3.4"));
+        assert!(single.contains(ip("1.2.3.4")));
+        assert!(!single.contains(ip("1.2.3.5")));
+    }
+}
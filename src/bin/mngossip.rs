@@ -1,6 +1,6 @@
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::time::Duration;
 
-use melnet::{NetState, Request};
+use melnet::NetState;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -19,26 +19,10 @@ fn main() -> anyhow::Result<()> {
         let tcp_listener = smol::net::TcpListener::bind("127.0.0.1:0").await?;
         println!("MY ADDRESS: {}", tcp_listener.local_addr()?);
         let nstate = NetState::new_with_name("gossip");
-        let seen_msgs = Arc::new(parking_lot::RwLock::new(HashSet::new()));
-        // register the verbs
-        nstate.listen("gossip", move |req: Request<GossipMsg>| {
-            let seen_msgs = seen_msgs.clone();
-            async move {
-                println!("received {:?}", req.body);
-                if seen_msgs.read().get(&req.body.id).is_none() {
-                    seen_msgs.write().insert(req.body.id);
-                    let body = req.body.clone();
-                    let state = req.state.clone();
-                    // spam to all my neighbors
-                    smolscale::spawn(async move {
-                        if let Err(e) = spam_neighbors(&state, body).await {
-                            println!("failed: {}", e);
-                        }
-                    })
-                    .detach();
-                }
-                Ok(())
-            }
+        // melnet takes care of deduplicating and passing on broadcasts
+        nstate.on_broadcast("gossip", |msg: GossipMsg| {
+            println!("received {:?}", msg);
+            true
         });
         nstate.start_server(tcp_listener);
         // listen
@@ -47,24 +31,15 @@ fn main() -> anyhow::Result<()> {
     }))
 }
 
-async fn spam_neighbors(nstate: &NetState, req: GossipMsg) -> anyhow::Result<()> {
-    for &neigh in nstate.routes().iter() {
-        melnet::request(neigh, "gossip", "gossip", req.clone()).await?;
-    }
-    Ok(())
-}
-
 async fn cmd_prompt(nstate: &NetState) {
     loop {
-        spam_neighbors(
-            nstate,
-            GossipMsg {
+        nstate.broadcast(
+            "gossip",
+            &GossipMsg {
                 id: rand::thread_rng().gen(),
                 body: "Hello World!".into(),
             },
-        )
-        .await
-        .unwrap();
+        );
         smol::Timer::after(Duration::from_secs(10)).await;
     }
 }
@@ -1,51 +1,241 @@
+use crate::crypt::{handshake_initiator, FrameCodec, NodeKey, PublicKey};
+use crate::framing::FrameLimits;
+use crate::transport::{Connector, TcpConnector};
 use crate::{common::*, pipeline::Pipeline};
 
 use crate::reqs::*;
 
-use async_net::TcpStream;
 use dashmap::DashMap;
 use lazy_static::lazy_static;
 
 use serde::{de::DeserializeOwned, Serialize};
+use smol::channel::Receiver;
 use smol::lock::Semaphore;
-use smol_timeout::TimeoutExt;
+use smol::prelude::*;
 
+use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 lazy_static! {
-    static ref CONN_POOL: Client = Client::default();
+    static ref CONN_POOL: Client = ClientBuilder::new()
+        .timeout(Duration::from_secs(60))
+        .build();
 }
 
-/// Does a melnet request to any given endpoint, using the global client.
+/// Does a melnet request to any given endpoint, using the global client. The global client gives up on requests after 60 seconds.
 pub async fn request<TInput: Serialize + Clone, TOutput: DeserializeOwned + std::fmt::Debug>(
     addr: SocketAddr,
     netname: &str,
     verb: &str,
     req: TInput,
 ) -> Result<TOutput> {
-    match CONN_POOL
-        .request(addr, netname, verb, req)
-        .timeout(Duration::from_secs(60))
-        .await
-    {
-        Some(v) => v,
-        None => Err(MelnetError::Network(std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            "long timeout at 60 seconds",
-        ))),
+    CONN_POOL.request(addr, netname, verb, req).await
+}
+
+const LEGACY_RECHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The longest a retry ever waits, however many retries came before it.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How a request is retried on transient network errors. Retries back off exponentially, starting from `initial_delay`, up to a minute between retries.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub initial_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            initial_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            initial_delay: Duration::from_secs(0),
+        }
+    }
+
+    /// How long to wait before the given retry, counting from zero.
+    fn delay(&self, count: u32) -> Duration {
+        2u32.checked_pow(count)
+            .and_then(|factor| self.initial_delay.checked_mul(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+}
+
+/// Per-request overrides of a [Client]'s settings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestOptions {
+    deadline: Option<Instant>,
+    retry: Option<RetryPolicy>,
+}
+
+impl RequestOptions {
+    /// Gives up on the request, including all its retries, at the given instant. If the client has a timeout of its own, whichever comes first wins.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Like `deadline`, but relative to now. Timeouts too long to have a deadline mean no deadline at all.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Instant::now().checked_add(timeout);
+        self
+    }
+
+    /// Retries the request with the given policy instead of the client's.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
     }
 }
 
-const POOL_SIZE: usize = 4;
+/// A builder for a [Client].
+#[derive(Clone)]
+pub struct ClientBuilder {
+    pool_size: usize,
+    idle_timeout: Duration,
+    retry: RetryPolicy,
+    max_concurrency: usize,
+    timeout: Option<Duration>,
+    static_key: Option<NodeKey>,
+    connector: Arc<dyn Connector>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            idle_timeout: Duration::from_secs(60),
+            retry: RetryPolicy::default(),
+            max_concurrency: 256,
+            timeout: None,
+            static_key: None,
+            connector: Arc::new(TcpConnector),
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Creates a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many connections are kept open to each server. Requests are spread randomly across them.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size.max(1);
+        self
+    }
+
+    /// Sets how long a connection is reused for before a fresh one is opened.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how requests are retried on transient network errors.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets how many requests the client can have in flight at once, across all servers.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// Sets how long to wait for a request, including all its retries, before giving up. By default, the client waits forever.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes the client encrypt all its connections, authenticating itself with the given static key.
+    pub fn static_key(mut self, key: NodeKey) -> Self {
+        self.static_key = Some(key);
+        self
+    }
+
+    /// Makes the client open its connections through the given connector, rather than over TCP.
+    pub fn connector(mut self, connector: impl Connector) -> Self {
+        self.connector = Arc::new(connector);
+        self
+    }
+
+    /// Builds the client.
+    pub fn build(self) -> Client {
+        Client {
+            pool: (0..self.pool_size).map(|_| DashMap::new()).collect(),
+            legacy: Default::default(),
+            pins: Default::default(),
+            identities: Default::default(),
+            limit: Semaphore::new(self.max_concurrency),
+            static_key: self.static_key.clone().unwrap_or_default(),
+            config: self,
+        }
+    }
+}
 
 /// Implements a thread-safe pool of connections to melnet, or any HTTP/1.1-style keepalive protocol, servers.
-#[derive(Default)]
 pub struct Client {
-    pool: [DashMap<SocketAddr, (Pipeline, Instant)>; POOL_SIZE],
+    pool: Vec<DashMap<SocketAddr, (Pipeline, Instant)>>,
+    // servers that only speak the legacy protocol, and when we found out
+    legacy: DashMap<SocketAddr, Instant>,
+    pins: DashMap<SocketAddr, PublicKey>,
+    // the static key each server last proved during the handshake
+    identities: DashMap<SocketAddr, PublicKey>,
+
+    limit: Semaphore,
+    // random unless configured, in which case every connection is encrypted
+    static_key: NodeKey,
+    config: ClientBuilder,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        ClientBuilder::new().build()
+    }
 }
 
 impl Client {
+    /// The settings this client was built with.
+    pub(crate) fn config(&self) -> &ClientBuilder {
+        &self.config
+    }
+
+    /// A new client with different settings, keeping this one's pins.
+    pub(crate) fn reconfigure(&self, config: ClientBuilder) -> Client {
+        let client = config.build();
+        for pin in self.pins.iter() {
+            client.pins.insert(*pin.key(), *pin.value());
+        }
+        client
+    }
+
+    /// The static key that the server at the given address proved during the last encrypted handshake with it, if any.
+    pub fn remote_key(&self, addr: SocketAddr) -> Option<PublicKey> {
+        self.identities.get(&addr).map(|k| *k)
+    }
+
+    /// Pins the server at the given address to a static public key. Connections to it are always encrypted, and fail unless the server proves it holds that key.
+    pub fn pin(&self, addr: SocketAddr, key: PublicKey) {
+        self.pins.insert(addr, key);
+        for pool in self.pool.iter() {
+            pool.remove(&addr);
+        }
+    }
+
     /// Does a melnet request to any given endpoint.
     pub async fn request<TInput: Serialize + Clone, TOutput: DeserializeOwned + std::fmt::Debug>(
         &self,
@@ -54,21 +244,95 @@ impl Client {
         verb: &str,
         req: TInput,
     ) -> Result<TOutput> {
-        for count in 0..5 {
-            match self.request_inner(addr, netname, verb, req.clone()).await {
-                Err(MelnetError::Network(err)) => {
-                    log::debug!(
-                        "retrying request {} to {} on transient network error {:?}",
-                        verb,
-                        addr,
-                        err
-                    );
-                    smol::Timer::after(Duration::from_secs_f64(0.1 * 2.0f64.powi(count))).await;
+        self.request_with(addr, netname, verb, req, RequestOptions::default())
+            .await
+    }
+
+    /// Does a melnet request to any given endpoint, overriding some of the client's settings.
+    pub async fn request_with<
+        TInput: Serialize + Clone,
+        TOutput: DeserializeOwned + std::fmt::Debug,
+    >(
+        &self,
+        addr: SocketAddr,
+        netname: &str,
+        verb: &str,
+        req: TInput,
+        opts: RequestOptions,
+    ) -> Result<TOutput> {
+        let retry = opts.retry.unwrap_or(self.config.retry);
+        // timeouts too long to add up to an instant are as good as no timeout at all
+        let timeout = self
+            .config
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let deadline = match (opts.deadline, timeout) {
+            (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+            (deadline, timeout) => deadline.or(timeout),
+        };
+        let attempts = async {
+            for count in 0..retry.retries {
+                match self
+                    .request_inner(addr, netname, verb, req.clone(), deadline)
+                    .await
+                {
+                    Err(MelnetError::Network(err)) => {
+                        log::debug!(
+                            "retrying request {} to {} on transient network error {:?}",
+                            verb,
+                            addr,
+                            err
+                        );
+                        smol::Timer::after(retry.delay(count)).await;
+                    }
+                    x => return x,
                 }
-                x => return x,
             }
+            self.request_inner(addr, netname, verb, req, deadline).await
+        };
+        if let Some(deadline) = deadline {
+            attempts
+                .or(async {
+                    smol::Timer::at(deadline).await;
+                    Err(MelnetError::Network(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "request timed out",
+                    )))
+                })
+                .await
+        } else {
+            attempts.await
         }
-        self.request_inner(addr, netname, verb, req).await
+    }
+
+    /// Subscribes to a topic published by the server at the given address. The server pushes messages back over the same connection until the returned [Subscription] is dropped, or the connection dies.
+    pub async fn subscribe<T: DeserializeOwned>(
+        &self,
+        addr: SocketAddr,
+        netname: &str,
+        topic: &str,
+    ) -> Result<Subscription<T>> {
+        let pool = &self.pool[fastrand::usize(0..self.pool.len())];
+        let (conn, _) = self.pooled_conn(pool, addr).await?;
+        let rr = RawRequest {
+            proto_ver: PROTO_VER,
+            id: 0,
+            netname: netname.to_owned(),
+            verb: SUBSCRIBE_VERB.to_owned(),
+            payload: stdcode::serialize(&topic).unwrap(),
+            budget: None,
+        };
+        let (id, recv) = conn.subscribe(rr).await?;
+        let mut sub = Subscription {
+            conn,
+            id,
+            netname: netname.to_owned(),
+            recv,
+            _phantom: PhantomData,
+        };
+        // the first response is the server's acknowledgement
+        sub.next_raw().await?;
+        Ok(sub)
     }
 
     async fn request_inner<TInput: Serialize, TOutput: DeserializeOwned + std::fmt::Debug>(
@@ -77,49 +341,56 @@ impl Client {
         netname: &str,
         verb: &str,
         req: TInput,
+        deadline: Option<Instant>,
     ) -> Result<TOutput> {
-        // // Semaphore
-        static GLOBAL_LIMIT: Semaphore = Semaphore::new(256);
         let start = Instant::now();
-        let _guard = GLOBAL_LIMIT.acquire().await;
+        let _guard = self.limit.acquire().await;
         log::debug!("acquired semaphore by {:?}", start.elapsed());
         let start = Instant::now();
         let pool = &self.pool[fastrand::usize(0..self.pool.len())];
-        let conn = if let Some(v) = pool.get(&addr).filter(|d| d.1.elapsed().as_secs() < 60) {
-            v.0.clone()
-        } else {
-            let t = TcpStream::connect(addr)
-                .await
-                .map_err(MelnetError::Network)?;
-            let pipe = Pipeline::new(t);
-            pool.insert(addr, (pipe.clone(), Instant::now()));
-            pipe
-        };
+        let (conn, fresh) = self.pooled_conn(pool, addr).await?;
         log::debug!("acquired connection by {:?}", start.elapsed());
 
+        let rr = RawRequest {
+            proto_ver: PROTO_VER,
+            id: 0,
+            netname: netname.to_owned(),
+            verb: verb.to_owned(),
+            payload: stdcode::serialize(&req).unwrap(),
+            budget: deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+        };
         let res = async {
             // send a request
-            let rr = stdcode::serialize(&RawRequest {
-                proto_ver: PROTO_VER,
-                netname: netname.to_owned(),
-                verb: verb.to_owned(),
-                payload: stdcode::serialize(&req).unwrap(),
-            })
-            .unwrap();
-            // read the response length
-            let response: RawResponse =
-                stdcode::deserialize(&conn.request(rr).await?).map_err(|e| {
-                    MelnetError::Network(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
-                })?;
-            let response = match response.kind.as_ref() {
-                "Ok" => stdcode::deserialize::<TOutput>(&response.body)
-                    .map_err(|_| MelnetError::Custom("stdcode error".to_owned()))?,
-                "NoVerb" => return Err(MelnetError::VerbNotFound),
-                _ => {
-                    return Err(MelnetError::Custom(
-                        String::from_utf8_lossy(&response.body).to_string(),
-                    ))
+            let response = match conn.request(rr.clone()).await {
+                Err(MelnetError::Network(err))
+                    if fresh
+                        && !conn.has_responded()
+                        && !self.is_legacy(addr)
+                        && !self.encrypts_to(addr) =>
+                {
+                    // servers that don't understand the current protocol complain in the legacy one, or just hang up on us, so try again with the legacy one
+                    log::debug!(
+                        "{} hung up on a fresh connection ({:?}), retrying with legacy protocol",
+                        addr,
+                        err
+                    );
+                    let legacy_conn = self.connect(addr, true).await?;
+                    let response = legacy_conn.request(rr).await?;
+                    if conn.got_legacy_reply() {
+                        self.legacy.insert(addr, Instant::now());
+                        pool.insert(addr, (legacy_conn, Instant::now()));
+                    } else {
+                        // current servers hang up on fresh connections too, such as when they're full or shutting down, so we don't give up on the current protocol just for that
+                        pool.remove(&addr);
+                    }
+                    response
                 }
+                res => res?,
+            };
+            let response = match response.result {
+                Ok(body) => stdcode::deserialize::<TOutput>(&body)
+                    .map_err(|_| MelnetError::Custom("stdcode error".to_owned()))?,
+                Err(err) => return Err(err.into()),
             };
             let elapsed = start.elapsed();
             if elapsed.as_secs_f64() > 3.0 {
@@ -141,4 +412,131 @@ impl Client {
             }
         }
     }
+
+    /// Gets a connection to the given address out of the pool, opening a new one if needed. Also returns whether the connection is new.
+    async fn pooled_conn(
+        &self,
+        pool: &DashMap<SocketAddr, (Pipeline, Instant)>,
+        addr: SocketAddr,
+    ) -> Result<(Pipeline, bool)> {
+        if let Some(v) = pool
+            .get(&addr)
+            .filter(|d| d.1.elapsed() < self.config.idle_timeout)
+        {
+            Ok((v.0.clone(), false))
+        } else {
+            let pipe = self.connect(addr, self.is_legacy(addr)).await?;
+            pool.insert(addr, (pipe.clone(), Instant::now()));
+            Ok((pipe, true))
+        }
+    }
+
+    /// Opens a new pipelined connection to the given address. Encrypted connections always use the current protocol.
+    async fn connect(&self, addr: SocketAddr, legacy: bool) -> Result<Pipeline> {
+        let mut t = self
+            .config
+            .connector
+            .connect(addr)
+            .await
+            .map_err(MelnetError::Network)?;
+        if self.encrypts_to(addr) {
+            let expected = self.pins.get(&addr).map(|k| *k);
+            let session =
+                handshake_initiator(&mut t, &self.static_key, expected, &FrameLimits::default())
+                    .await?;
+            self.identities.insert(addr, session.remote_key());
+            Ok(Pipeline::new(t, FrameCodec::encrypted(session)))
+        } else if legacy {
+            Ok(Pipeline::new_legacy(t))
+        } else {
+            Ok(Pipeline::new(t, FrameCodec::default()))
+        }
+    }
+
+    /// Whether connections to the given address are encrypted.
+    fn encrypts_to(&self, addr: SocketAddr) -> bool {
+        self.config.static_key.is_some() || self.pins.contains_key(&addr)
+    }
+
+    /// Whether the given server is known to only speak the legacy protocol. This is forgotten after a while, in case the server gets upgraded.
+    fn is_legacy(&self, addr: SocketAddr) -> bool {
+        self.legacy
+            .get(&addr)
+            .map(|t| t.elapsed() < LEGACY_RECHECK_INTERVAL)
+            .unwrap_or(false)
+    }
+}
+
+/// A subscription to a topic on a server, created by [Client::subscribe]. Dropping it unsubscribes.
+pub struct Subscription<T> {
+    conn: Pipeline,
+    id: u64,
+    netname: String,
+    recv: Receiver<RawResponse>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Subscription<T> {
+    /// Waits for the next message. Messages the client could not keep up with are dropped, and errors are permanent.
+    pub async fn next(&mut self) -> Result<T> {
+        let body = self.next_raw().await?;
+        stdcode::deserialize(&body).map_err(|_| MelnetError::Custom("stdcode error".to_owned()))
+    }
+
+    async fn next_raw(&mut self) -> Result<Vec<u8>> {
+        let recv = &self.recv;
+        let resp = async {
+            recv.recv().await.map_err(|_| {
+                MelnetError::Network(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "subscription ended",
+                ))
+            })
+        }
+        .or(async { Err(self.conn.dead().await) })
+        .await?;
+        Ok(resp.result?)
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.conn.cancel_subscription(self.id);
+        let conn = self.conn.clone();
+        let rr = RawRequest {
+            proto_ver: PROTO_VER,
+            id: 0,
+            netname: std::mem::take(&mut self.netname),
+            verb: UNSUBSCRIBE_VERB.to_owned(),
+            payload: stdcode::serialize(&self.id).unwrap(),
+            budget: None,
+        };
+        smolscale::spawn(async move {
+            let _ = conn.request(rr).await;
+        })
+        .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays_double_up_to_a_cap() {
+        let policy = RetryPolicy {
+            retries: u32::MAX,
+            initial_delay: Duration::from_millis(100),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+        assert_eq!(policy.delay(20), MAX_RETRY_DELAY);
+        assert_eq!(policy.delay(u32::MAX), MAX_RETRY_DELAY);
+        let huge = RetryPolicy {
+            retries: 1,
+            initial_delay: Duration::MAX,
+        };
+        assert_eq!(huge.delay(0), MAX_RETRY_DELAY);
+        assert_eq!(RetryPolicy::none().delay(10), Duration::from_secs(0));
+    }
 }
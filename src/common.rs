@@ -1,5 +1,7 @@
+use crate::framing::{FrameLimits, FrameReader};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use smol::prelude::*;
-use std::{pin::Pin};
+use std::pin::Pin;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, MelnetError>;
@@ -15,6 +17,16 @@ pub enum MelnetError {
     InternalServerError,
     #[error("network error: `{0}`")]
     Network(std::io::Error),
+    #[error("bad request: `{0}`")]
+    BadRequest(RemoteError),
+    #[error("not found: `{0}`")]
+    NotFound(RemoteError),
+    #[error("unauthorized: `{0}`")]
+    Unauthorized(RemoteError),
+    #[error("server overloaded: `{0}`")]
+    Overloaded(RemoteError),
+    #[error("server timed out: `{0}`")]
+    Timeout(RemoteError),
 }
 
 impl Clone for MelnetError {
@@ -26,12 +38,97 @@ impl Clone for MelnetError {
             MelnetError::Network(err) => {
                 MelnetError::Network(std::io::Error::new(err.kind(), err.to_string()))
             }
+            MelnetError::BadRequest(err) => MelnetError::BadRequest(err.clone()),
+            MelnetError::NotFound(err) => MelnetError::NotFound(err.clone()),
+            MelnetError::Unauthorized(err) => MelnetError::Unauthorized(err.clone()),
+            MelnetError::Overloaded(err) => MelnetError::Overloaded(err.clone()),
+            MelnetError::Timeout(err) => MelnetError::Timeout(err.clone()),
         }
     }
 }
 
-pub const PROTO_VER: u8 = 1;
+impl From<RemoteError> for MelnetError {
+    fn from(err: RemoteError) -> Self {
+        match err.code {
+            ErrorCode::Custom => MelnetError::Custom(err.message),
+            ErrorCode::NoVerb => MelnetError::VerbNotFound,
+            ErrorCode::Internal => MelnetError::InternalServerError,
+            ErrorCode::BadRequest => MelnetError::BadRequest(err),
+            ErrorCode::NotFound => MelnetError::NotFound(err),
+            ErrorCode::Unauthorized => MelnetError::Unauthorized(err),
+            ErrorCode::Overloaded => MelnetError::Overloaded(err),
+            ErrorCode::Timeout => MelnetError::Timeout(err),
+        }
+    }
+}
+
+/// The category of a failure on the server side, as carried on the wire.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// A free-form error from a verb handler. Plain `anyhow` errors returned by handlers have this code.
+    Custom,
+    /// The server has no handler for the verb.
+    NoVerb,
+    /// Something went wrong inside the server itself.
+    Internal,
+    /// The request was malformed, for example because its body could not be decoded.
+    BadRequest,
+    /// The thing the request asked about doesn't exist.
+    NotFound,
+    /// The caller isn't allowed to make this request.
+    Unauthorized,
+    /// The server is too busy to handle the request right now.
+    Overloaded,
+    /// The server gave up on the request before it finished.
+    Timeout,
+}
+
+/// An error produced by the server, carrying an [ErrorCode], a human-readable message, and optionally a stdcode-encoded payload with more details.
+///
+/// Verb handlers return a `RemoteError` (wrapped in an `anyhow::Error`) to fail with a specific code; on the client, it shows up as the corresponding [MelnetError] variant.
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[error("{message}")]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Vec<u8>>,
+}
+
+impl RemoteError {
+    /// Creates an error with the given code and message.
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    /// Attaches a structured payload to the error.
+    pub fn with_details<T: Serialize>(mut self, details: &T) -> Self {
+        self.details = Some(stdcode::serialize(details).unwrap());
+        self
+    }
+
+    /// Decodes the structured payload, if there is one.
+    pub fn details<T: DeserializeOwned>(&self) -> Option<T> {
+        self.details
+            .as_ref()
+            .and_then(|d| stdcode::deserialize(d).ok())
+    }
+}
+
+/// The current protocol version. Version 2 requests carry an ID, so a connection can have many requests in flight at once.
+pub const PROTO_VER: u8 = 2;
+/// The original, strictly in-order protocol version. Still accepted by servers and spoken by clients to servers that don't understand version 2.
+pub const LEGACY_PROTO_VER: u8 = 1;
 pub const MAX_MSG_SIZE: u32 = 50 * 1024 * 1024;
+/// The built-in verb that subscribes to a topic. Its body is the topic name.
+pub const SUBSCRIBE_VERB: &str = "subscribe";
+/// The built-in verb that cancels a subscription. Its body is the request ID of the subscription.
+pub const UNSUBSCRIBE_VERB: &str = "unsubscribe";
+/// The built-in verb that cancels an in-flight request. Its body is the ID of the request to cancel, and the server doesn't respond to it.
+pub const CANCEL_VERB: &str = "cancel";
 
 pub async fn write_len_bts<T: AsyncWrite + Unpin>(mut conn: T, rr: &[u8]) -> Result<()> {
     debug_assert!(rr.len() < MAX_MSG_SIZE as usize);
@@ -43,23 +140,9 @@ pub async fn write_len_bts<T: AsyncWrite + Unpin>(mut conn: T, rr: &[u8]) -> Res
     Ok(())
 }
 
-pub async fn read_len_bts<T: AsyncRead + Unpin>(mut conn: T) -> Result<Vec<u8>> {
-    // read the response length
-    let mut response_len = [0; 4];
-    conn.read_exact(&mut response_len)
+/// Reads a length-prefixed frame. The buffer grows as bytes arrive, so a peer can't make us allocate a huge frame just by claiming one.
+pub async fn read_len_bts<T: AsyncRead + Unpin>(conn: T) -> Result<Vec<u8>> {
+    FrameReader::new(&FrameLimits::default())
+        .read_piece(conn, MAX_MSG_SIZE as usize)
         .await
-        .map_err(MelnetError::Network)?;
-    let response_len = u32::from_be_bytes(response_len);
-    if response_len > MAX_MSG_SIZE {
-        return Err(MelnetError::Network(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "response too big",
-        )));
-    }
-    // read the response
-    let mut response_buf = vec![0; response_len as usize];
-    conn.read_exact(&mut response_buf)
-        .await
-        .map_err(MelnetError::Network)?;
-    Ok(response_buf)
 }
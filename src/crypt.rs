@@ -0,0 +1,298 @@
+use std::fmt::{Debug, Display};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use smol::prelude::*;
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::StatelessTransportState;
+
+use crate::framing::{FrameLimits, FrameReader, FrameReservation};
+use crate::{write_len_bts, MelnetError, Result};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PROLOGUE: &[u8] = b"melnet";
+
+/// The first byte of the first frame of an encrypted connection. Plaintext connections start with a protocol version instead, which is never zero.
+pub(crate) const HANDSHAKE_MARKER: u8 = 0;
+
+/// The largest message Noise ever sends, whether during the handshake or after.
+const MAX_NOISE_MSG: usize = 65535;
+
+// each encrypted chunk carries a one-byte "more chunks follow" flag along with the data
+const MAX_CHUNK_SIZE: usize = MAX_NOISE_MSG - 16 - 1;
+
+/// The static public key of a melnet node, which authenticates it during the encrypted handshake.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// The static keypair of a melnet node. The default is a freshly generated random key.
+#[derive(Clone)]
+pub struct NodeKey {
+    secret: [u8; 32],
+    public: PublicKey,
+}
+
+impl Debug for NodeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeKey")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for NodeKey {
+    fn default() -> Self {
+        Self::generate()
+    }
+}
+
+impl NodeKey {
+    /// Generates a new random key.
+    pub fn generate() -> Self {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .expect("could not generate keypair");
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&keypair.private);
+        Self::from_secret(secret)
+    }
+
+    /// Creates a key from a 32-byte X25519 secret, such as one that was previously saved to disk.
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("curve25519 not supported");
+        dh.set(&secret);
+        let mut public = [0u8; 32];
+        public.copy_from_slice(dh.pubkey());
+        Self {
+            secret,
+            public: PublicKey(public),
+        }
+    }
+
+    /// Returns the secret half of the key.
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret
+    }
+
+    /// Returns the public half of the key.
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+}
+
+/// An established encrypted session, sitting between the framing of [write_len_bts]/[read_len_bts](crate::read_len_bts) and the requests and responses carried inside.
+pub(crate) struct Session {
+    transport: StatelessTransportState,
+    remote: PublicKey,
+    // held across encrypt-and-write so that frames hit the wire in nonce order
+    send_nonce: smol::lock::Mutex<u64>,
+    recv_nonce: AtomicU64,
+}
+
+impl Session {
+    /// The authenticated static public key of the other side.
+    pub fn remote_key(&self) -> PublicKey {
+        self.remote
+    }
+
+    /// Encrypts and writes a whole frame.
+    pub async fn write_frame<T: AsyncWrite + Unpin>(&self, mut conn: T, bts: &[u8]) -> Result<()> {
+        let mut nonce = self.send_nonce.lock().await;
+        let mut chunks = bts.chunks(MAX_CHUNK_SIZE).peekable();
+        let mut plain = Vec::with_capacity(MAX_CHUNK_SIZE + 1);
+        let mut cipher = vec![0u8; MAX_NOISE_MSG];
+        loop {
+            let chunk = chunks.next().unwrap_or_default();
+            plain.clear();
+            plain.push(chunks.peek().is_some() as u8);
+            plain.extend_from_slice(chunk);
+            let n = self
+                .transport
+                .write_message(*nonce, &plain, &mut cipher)
+                .map_err(noise_error)?;
+            *nonce += 1;
+            write_len_bts(&mut conn, &cipher[..n]).await?;
+            if chunks.peek().is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads and decrypts a whole frame, along with the memory reserved for it. Must not be called concurrently.
+    pub async fn read_frame<T: AsyncRead + Unpin>(
+        &self,
+        mut conn: T,
+        limits: &FrameLimits,
+    ) -> Result<(Vec<u8>, FrameReservation)> {
+        let mut reader = FrameReader::new(limits);
+        let mut frame = Vec::new();
+        let mut plain = vec![0u8; MAX_NOISE_MSG];
+        loop {
+            let cipher = reader.read_piece(&mut conn, MAX_NOISE_MSG).await?;
+            let nonce = self.recv_nonce.fetch_add(1, Ordering::Relaxed);
+            let n = self
+                .transport
+                .read_message(nonce, &cipher, &mut plain)
+                .map_err(noise_error)?;
+            if n == 0 || frame.len() + n - 1 > reader.max_size() {
+                return Err(invalid_data("bad encrypted chunk"));
+            }
+            frame.extend_from_slice(&plain[1..n]);
+            if plain[0] == 0 {
+                return Ok((frame, reader.into_reservation()));
+            }
+        }
+    }
+}
+
+/// Runs the initiator side of the handshake, optionally requiring the responder to have a particular static key. Handshake messages are read within the given limits.
+pub(crate) async fn handshake_initiator<T: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: T,
+    key: &NodeKey,
+    expected: Option<PublicKey>,
+    limits: &FrameLimits,
+) -> Result<Session> {
+    let secret = key.secret;
+    let mut hs = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+        .prologue(NOISE_PROLOGUE)
+        .local_private_key(&secret)
+        .build_initiator()
+        .map_err(noise_error)?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG];
+    // -> e
+    let n = hs.write_message(&[], &mut buf).map_err(noise_error)?;
+    let mut msg = vec![HANDSHAKE_MARKER];
+    msg.extend_from_slice(&buf[..n]);
+    write_len_bts(&mut conn, &msg).await?;
+    // <- e, ee, s, es
+    let msg = FrameReader::new(limits)
+        .read_piece(&mut conn, MAX_NOISE_MSG)
+        .await?;
+    hs.read_message(&msg, &mut buf).map_err(noise_error)?;
+    let remote = remote_static(&hs)?;
+    if let Some(expected) = expected {
+        if remote != expected {
+            return Err(invalid_data("server presented an unexpected static key"));
+        }
+    }
+    // -> s, se
+    let n = hs.write_message(&[], &mut buf).map_err(noise_error)?;
+    write_len_bts(&mut conn, &buf[..n]).await?;
+    into_session(hs, remote)
+}
+
+/// Runs the responder side of the handshake, given the first frame the initiator sent. The rest of the handshake is read within the given limits.
+pub(crate) async fn handshake_responder<T: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: T,
+    key: &NodeKey,
+    first_frame: &[u8],
+    limits: &FrameLimits,
+) -> Result<Session> {
+    let secret = key.secret;
+    let mut hs = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+        .prologue(NOISE_PROLOGUE)
+        .local_private_key(&secret)
+        .build_responder()
+        .map_err(noise_error)?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG];
+    // -> e
+    match first_frame.split_first() {
+        Some((&HANDSHAKE_MARKER, msg)) => {
+            hs.read_message(msg, &mut buf).map_err(noise_error)?;
+        }
+        _ => return Err(invalid_data("not a handshake")),
+    }
+    // <- e, ee, s, es
+    let n = hs.write_message(&[], &mut buf).map_err(noise_error)?;
+    write_len_bts(&mut conn, &buf[..n]).await?;
+    // -> s, se
+    let msg = FrameReader::new(limits)
+        .read_piece(&mut conn, MAX_NOISE_MSG)
+        .await?;
+    hs.read_message(&msg, &mut buf).map_err(noise_error)?;
+    let remote = remote_static(&hs)?;
+    into_session(hs, remote)
+}
+
+fn remote_static(hs: &snow::HandshakeState) -> Result<PublicKey> {
+    let remote = hs
+        .get_remote_static()
+        .ok_or_else(|| invalid_data("no remote static key"))?;
+    let mut pk = [0u8; 32];
+    pk.copy_from_slice(remote);
+    Ok(PublicKey(pk))
+}
+
+fn into_session(hs: snow::HandshakeState, remote: PublicKey) -> Result<Session> {
+    Ok(Session {
+        transport: hs.into_stateless_transport_mode().map_err(noise_error)?,
+        remote,
+        send_nonce: smol::lock::Mutex::new(0),
+        recv_nonce: AtomicU64::new(0),
+    })
+}
+
+/// How frames are carried over a connection: either in plaintext, or inside an encrypted session.
+#[derive(Clone, Default)]
+pub(crate) struct FrameCodec(Option<Arc<Session>>);
+
+impl FrameCodec {
+    /// A codec that encrypts everything with the given session.
+    pub fn encrypted(session: Session) -> Self {
+        Self(Some(Arc::new(session)))
+    }
+
+    /// Writes a whole frame.
+    pub async fn write<T: AsyncWrite + Unpin>(&self, conn: T, bts: &[u8]) -> Result<()> {
+        match &self.0 {
+            Some(session) => session.write_frame(conn, bts).await,
+            None => write_len_bts(conn, bts).await,
+        }
+    }
+
+    /// Reads a whole frame within the given limits, along with the memory reserved for it.
+    pub async fn read<T: AsyncRead + Unpin>(
+        &self,
+        conn: T,
+        limits: &FrameLimits,
+    ) -> Result<(Vec<u8>, FrameReservation)> {
+        match &self.0 {
+            Some(session) => session.read_frame(conn, limits).await,
+            None => {
+                let mut reader = FrameReader::new(limits);
+                let frame = reader.read_piece(conn, limits.max_size).await?;
+                Ok((frame, reader.into_reservation()))
+            }
+        }
+    }
+}
+
+fn noise_error(err: snow::Error) -> MelnetError {
+    invalid_data(&format!("noise error: {}", err))
+}
+
+fn invalid_data(msg: &str) -> MelnetError {
+    MelnetError::Network(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        msg.to_owned(),
+    ))
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+use crate::PeerId;
+
+/// The verb that tells the caller which address the server saw it connect from.
+pub(crate) const OBSERVED_ADDR_VERB: &str = "observed_addr";
+
+/// How many peers' observations we remember.
+const MAX_VOTERS: usize = 32;
+
+/// How many peers have to agree before we believe them.
+const MIN_VOTES: usize = 3;
+
+/// Observations of our own IP address by other peers, one vote per peer.
+#[derive(Default)]
+pub(crate) struct AddrVotes {
+    votes: HashMap<PeerId, IpAddr>,
+    // voters from oldest to newest
+    order: VecDeque<PeerId>,
+}
+
+impl AddrVotes {
+    /// Records what a peer saw, replacing its previous vote. The oldest votes are forgotten once there are too many.
+    pub fn vote(&mut self, voter: PeerId, ip: IpAddr) {
+        if self.votes.insert(voter, ip).is_some() {
+            self.order.retain(|v| *v != voter);
+        }
+        self.order.push_back(voter);
+        while self.order.len() > MAX_VOTERS {
+            if let Some(old) = self.order.pop_front() {
+                self.votes.remove(&old);
+            }
+        }
+    }
+
+    /// The address that a strict majority of voters agree on, if there are enough of them.
+    pub fn winner(&self) -> Option<IpAddr> {
+        let mut tally: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.votes.values() {
+            *tally.entry(*ip).or_default() += 1;
+        }
+        let (ip, count) = tally.into_iter().max_by_key(|(_, count)| *count)?;
+        if count >= MIN_VOTES && count * 2 > self.votes.len() {
+            Some(ip)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn voter(port: u16) -> PeerId {
+        PeerId::Addr(SocketAddr::from(([10, 0, 0, 1], port)))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn needs_enough_votes() {
+        let mut votes = AddrVotes::default();
+        votes.vote(voter(1), ip("1.2.3.4"));
+        votes.vote(voter(2), ip("1.2.3.4"));
+        assert_eq!(votes.winner(), None);
+        votes.vote(voter(3), ip("1.2.3.4"));
+        assert_eq!(votes.winner(), Some(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn needs_a_strict_majority() {
+        let mut votes = AddrVotes::default();
+        for port in 0..3 {
+            votes.vote(voter(port), ip("1.2.3.4"));
+        }
+        for port in 3..6 {
+            votes.vote(voter(port), ip("5.6.7.8"));
+        }
+        assert_eq!(votes.winner(), None);
+        votes.vote(voter(6), ip("5.6.7.8"));
+        assert_eq!(votes.winner(), Some(ip("5.6.7.8")));
+    }
+
+    #[test]
+    fn one_vote_per_voter() {
+        let mut votes = AddrVotes::default();
+        for _ in 0..5 {
+            votes.vote(voter(1), ip("1.2.3.4"));
+        }
+        assert_eq!(votes.winner(), None);
+        votes.vote(voter(2), ip("1.2.3.4"));
+        votes.vote(voter(3), ip("1.2.3.4"));
+        votes.vote(voter(1), ip("5.6.7.8"));
+        assert_eq!(votes.winner(), None);
+    }
+
+    #[test]
+    fn forgets_oldest_voters() {
+        let mut votes = AddrVotes::default();
+        for port in 0..MAX_VOTERS as u16 {
+            votes.vote(voter(port), ip("1.2.3.4"));
+        }
+        for port in 0..MAX_VOTERS as u16 {
+            votes.vote(voter(1000 + port), ip("5.6.7.8"));
+        }
+        assert_eq!(votes.winner(), Some(ip("5.6.7.8")));
+    }
+}
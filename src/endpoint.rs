@@ -1,12 +1,14 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
 use serde::{de::DeserializeOwned, Serialize};
 use smol::prelude::*;
 
-use crate::MelnetError;
+use crate::{ErrorCode, PublicKey, RemoteError};
 
-/// An Endpoint asynchronously responds to Requests.
+/// An Endpoint asynchronously responds to Requests. To fail with a specific [ErrorCode], return a [RemoteError]; any other error is sent back as [ErrorCode::Custom].
 #[async_trait]
 pub trait Endpoint<Req: DeserializeOwned + Send + 'static, Resp: Serialize>: Send + Sync {
     /// Handle a request. This should not block. Implementations should do things like move the Request to background tasks/threads to avoid this.
@@ -32,10 +34,10 @@ pub(crate) fn responder_to_closure<
     Resp: Serialize + Send + 'static,
 >(
     state: crate::NetState,
-    responder: impl Endpoint<Req, Resp> + 'static + Send + Sync,
+    responder: impl Endpoint<Req, Resp> + 'static,
 ) -> BoxedResponder {
     let responder = Arc::new(responder);
-    let clos = move |bts: &[u8]| {
+    let clos = move |bts: &[u8], meta: RequestMeta| {
         let decoded: Result<Req, _> = stdcode::deserialize(bts);
         let responder = responder.clone();
         let state = state.clone();
@@ -46,16 +48,21 @@ pub(crate) fn responder_to_closure<
                         .respond(Request {
                             body: decoded,
                             state,
+                            meta,
                         })
                         .await
-                        .map_err(|e| MelnetError::Custom(e.to_string()))?;
+                        .map_err(|e| match e.downcast::<RemoteError>() {
+                            Ok(err) => err,
+                            Err(e) => RemoteError::new(ErrorCode::Custom, e),
+                        })?;
                     Ok(stdcode::serialize(&response).unwrap())
                 };
                 response_fut.boxed()
             }
             Err(e) => {
                 log::warn!("issue decoding request: {}", e);
-                async { Err(MelnetError::InternalServerError) }.boxed()
+                let err = RemoteError::new(ErrorCode::BadRequest, format!("cannot decode: {}", e));
+                async { Err(err) }.boxed()
             }
         }
     };
@@ -65,7 +72,11 @@ pub(crate) fn responder_to_closure<
 #[allow(clippy::type_complexity)]
 #[derive(Clone)]
 pub(crate) struct BoxedResponder(
-    pub Arc<dyn Fn(&[u8]) -> smol::future::Boxed<crate::Result<Vec<u8>>> + Send + Sync>,
+    pub  Arc<
+        dyn Fn(&[u8], RequestMeta) -> smol::future::Boxed<Result<Vec<u8>, RemoteError>>
+            + Send
+            + Sync,
+    >,
 );
 
 /// A `Request<Req, Resp>` carries a stdcode-compatible request of type `Req and can be responded to with responses of type Resp.
@@ -73,4 +84,42 @@ pub(crate) struct BoxedResponder(
 pub struct Request<Req: DeserializeOwned> {
     pub body: Req,
     pub state: crate::NetState,
+    /// Who sent the request, and when it arrived.
+    pub meta: RequestMeta,
+}
+
+impl<Req: DeserializeOwned> Request<Req> {
+    /// How much time is left before the caller stops waiting for a response, if it said.
+    pub fn remaining_budget(&self) -> Option<Duration> {
+        self.meta
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Raises the reputation of whoever sent the request.
+    pub fn reward_sender(&self, amount: f64) {
+        self.state
+            .inbound_reputation_delta(self.meta.remote_addr, self.meta.remote_key, amount);
+    }
+
+    /// Lowers the reputation of whoever sent the request, for example because it carried invalid data. Senders that didn't prove a static key are only known by IP, so this lowers the reputation of every route at that IP.
+    pub fn penalize_sender(&self, amount: f64) {
+        self.state
+            .inbound_reputation_delta(self.meta.remote_addr, self.meta.remote_key, -amount);
+    }
+}
+
+/// Where and when a request came from.
+#[derive(Clone, Debug)]
+pub struct RequestMeta {
+    /// The address the request was sent from.
+    pub remote_addr: SocketAddr,
+    /// The static key the other side proved, if the connection is encrypted.
+    pub remote_key: Option<PublicKey>,
+    /// The protocol version the request was sent with.
+    pub proto_ver: u8,
+    /// When the server finished reading the request.
+    pub received: SystemTime,
+    /// When the client stops waiting for a response, if it said. The server gives up on the request at that point. Handlers can give `RequestOptions::deadline` this deadline, so that the requests they make on the caller's behalf give up at the same time.
+    pub deadline: Option<Instant>,
 }
@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use smol::prelude::*;
+
+use crate::{MelnetError, Result, MAX_MSG_SIZE};
+
+/// How much of a frame we buffer at a time, so that memory grows with the bytes that actually arrive rather than with the length the peer claims.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Limits on reading a single frame.
+#[derive(Clone)]
+pub(crate) struct FrameLimits {
+    /// The largest frame we accept.
+    pub max_size: usize,
+    /// How long the rest of a frame may take to arrive, once its first length prefix has.
+    pub deadline: Option<Duration>,
+    /// Memory shared with every other frame being read at the same time.
+    pub budget: Option<Arc<FrameBudget>>,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_size: MAX_MSG_SIZE as usize,
+            deadline: None,
+            budget: None,
+        }
+    }
+}
+
+/// A bound on the memory that all frames being read at once take up together.
+pub(crate) struct FrameBudget {
+    capacity: usize,
+    used: AtomicUsize,
+}
+
+impl FrameBudget {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_reserve(&self, n: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(n).filter(|&total| total <= self.capacity)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, n: usize) {
+        self.used.fetch_sub(n, Ordering::SeqCst);
+    }
+}
+
+/// Memory reserved from a [FrameBudget] for a frame, given back when dropped. Whoever holds on to a frame, or anything decoded from it, should hold on to its reservation too.
+#[derive(Default)]
+pub(crate) struct FrameReservation {
+    budget: Option<Arc<FrameBudget>>,
+    reserved: usize,
+}
+
+impl Drop for FrameReservation {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.reserved);
+        }
+    }
+}
+
+/// Reads one frame, which may arrive as several length-prefixed pieces, within some [FrameLimits]. Memory reserved for the frame is given back when the reader is dropped, unless it's kept with `FrameReader::into_reservation`.
+pub(crate) struct FrameReader<'a> {
+    limits: &'a FrameLimits,
+    deadline: Option<Instant>,
+    reservation: FrameReservation,
+}
+
+impl<'a> FrameReader<'a> {
+    pub fn new(limits: &'a FrameLimits) -> Self {
+        Self {
+            limits,
+            deadline: None,
+            reservation: FrameReservation {
+                budget: limits.budget.clone(),
+                reserved: 0,
+            },
+        }
+    }
+
+    /// The largest frame we accept.
+    pub fn max_size(&self) -> usize {
+        self.limits.max_size
+    }
+
+    /// Reads one length-prefixed piece of at most `max_len` bytes.
+    pub async fn read_piece<T: AsyncRead + Unpin>(
+        &mut self,
+        mut conn: T,
+        max_len: usize,
+    ) -> Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.before_deadline(conn.read_exact(&mut len)).await?;
+        // waiting for a frame to start is fine, but once it has, it has to finish in time
+        if self.deadline.is_none() {
+            self.deadline = self
+                .limits
+                .deadline
+                .and_then(|d| Instant::now().checked_add(d));
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > max_len {
+            return Err(invalid_data("frame too big"));
+        }
+        let mut buf = Vec::new();
+        while buf.len() < len {
+            let chunk = (len - buf.len()).min(READ_CHUNK);
+            self.reserve(chunk)?;
+            let start = buf.len();
+            buf.resize(start + chunk, 0);
+            self.before_deadline(conn.read_exact(&mut buf[start..]))
+                .await?;
+        }
+        Ok(buf)
+    }
+
+    /// Keeps the memory reserved for the frame read so far until the returned reservation is dropped.
+    pub fn into_reservation(self) -> FrameReservation {
+        self.reservation
+    }
+
+    fn reserve(&mut self, n: usize) -> Result<()> {
+        if let Some(budget) = &self.reservation.budget {
+            if !budget.try_reserve(n) {
+                return Err(MelnetError::Network(std::io::Error::new(
+                    std::io::ErrorKind::OutOfMemory,
+                    "too much memory taken by frames in flight",
+                )));
+            }
+            self.reservation.reserved += n;
+        }
+        Ok(())
+    }
+
+    async fn before_deadline(&self, fut: impl Future<Output = std::io::Result<()>>) -> Result<()> {
+        let res = match self.deadline {
+            Some(deadline) => {
+                fut.or(async {
+                    smol::Timer::at(deadline).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "frame took too long to arrive",
+                    ))
+                })
+                .await
+            }
+            None => fut.await,
+        };
+        res.map_err(MelnetError::Network)
+    }
+}
+
+fn invalid_data(msg: &str) -> MelnetError {
+    MelnetError::Network(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        msg.to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize) -> Vec<u8> {
+        let mut frame = (len as u32).to_be_bytes().to_vec();
+        frame.resize(4 + len, 0);
+        frame
+    }
+
+    fn read(limits: &FrameLimits, len: usize) -> Result<(Vec<u8>, FrameReservation)> {
+        smol::block_on(async {
+            let mut reader = FrameReader::new(limits);
+            let frame = reader.read_piece(&frame(len)[..], limits.max_size).await?;
+            Ok((frame, reader.into_reservation()))
+        })
+    }
+
+    #[test]
+    fn reservations_last_until_dropped() {
+        let limits = FrameLimits {
+            budget: Some(Arc::new(FrameBudget::new(100))),
+            ..FrameLimits::default()
+        };
+        let (first, reservation) = read(&limits, 60).unwrap();
+        assert_eq!(first.len(), 60);
+        assert!(read(&limits, 60).is_err());
+        drop(reservation);
+        assert!(read(&limits, 60).is_ok());
+        // reservations nobody holds on to are given back right away
+        assert!(read(&limits, 60).is_ok());
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let limits = FrameLimits {
+            max_size: 10,
+            ..FrameLimits::default()
+        };
+        assert!(read(&limits, 10).is_ok());
+        assert!(read(&limits, 11).is_err());
+    }
+}
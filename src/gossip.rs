@@ -0,0 +1,77 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// The verb that carries broadcast messages between nodes.
+pub(crate) const GOSSIP_VERB: &str = "gossip_msg";
+
+/// Settings for how broadcast messages spread through the network.
+#[derive(Clone, Copy, Debug)]
+pub struct GossipConfig {
+    /// How many random neighbors each node forwards a message to.
+    pub fanout: usize,
+    /// How many hops a message travels before nodes stop forwarding it.
+    pub ttl: u8,
+    /// How many message IDs each node remembers, so that it handles and forwards each message only once.
+    pub seen_capacity: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 8,
+            ttl: 8,
+            seen_capacity: 100_000,
+        }
+    }
+}
+
+/// A broadcast message, as it travels between nodes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct GossipMsg {
+    pub id: u128,
+    pub topic: String,
+    pub ttl: u8,
+    pub body: Vec<u8>,
+}
+
+pub(crate) type GossipHandler = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Broadcast state shared between all copies of a `NetState`.
+#[derive(Default)]
+pub(crate) struct Gossip {
+    pub handlers: DashMap<String, GossipHandler>,
+    seen: Mutex<SeenCache>,
+}
+
+impl Gossip {
+    /// Remembers a message ID, returning whether it was new.
+    pub fn see(&self, id: u128, capacity: usize) -> bool {
+        self.seen.lock().insert(id, capacity)
+    }
+}
+
+/// A set of recently seen message IDs that forgets the oldest ones once it's full.
+#[derive(Default)]
+struct SeenCache {
+    set: HashSet<u128>,
+    order: VecDeque<u128>,
+}
+
+impl SeenCache {
+    fn insert(&mut self, id: u128, capacity: usize) -> bool {
+        if !self.set.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        while self.order.len() > capacity.max(1) {
+            if let Some(old) = self.order.pop_front() {
+                self.set.remove(&old);
+            }
+        }
+        true
+    }
+}
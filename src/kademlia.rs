@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use crate::PublicKey;
+
+/// The verb that looks up the peers a node knows closest to some key.
+pub(crate) const FIND_NODE_VERB: &str = "find_node";
+
+/// How many nodes an iterative lookup queries at once.
+pub(crate) const LOOKUP_PARALLELISM: usize = 3;
+
+/// The XOR distance between two keys, which compares as a big-endian integer.
+pub(crate) fn distance(a: &PublicKey, b: &PublicKey) -> [u8; 32] {
+    let mut d = [0u8; 32];
+    for (i, d) in d.iter_mut().enumerate() {
+        *d = a.0[i] ^ b.0[i];
+    }
+    d
+}
+
+/// A Kademlia routing table: peers with static keys, sorted into k-buckets by XOR distance from our own key.
+pub(crate) struct KBuckets {
+    local: PublicKey,
+    k: usize,
+    // bucket i holds peers whose distance from us has exactly i leading zero bits. Each bucket goes from least to most recently seen.
+    buckets: Vec<VecDeque<(PublicKey, SocketAddr)>>,
+}
+
+impl KBuckets {
+    /// Creates an empty table around our own key, keeping at most k peers per bucket.
+    pub fn new(local: PublicKey, k: usize) -> Self {
+        Self {
+            local,
+            k: k.max(1),
+            buckets: (0..256).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    /// The number of peers each bucket holds.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    fn bucket_index(&self, key: &PublicKey) -> Option<usize> {
+        let d = distance(&self.local, key);
+        let mut zeros = 0;
+        for b in d {
+            if b != 0 {
+                return Some(zeros + b.leading_zeros() as usize);
+            }
+            zeros += 8;
+        }
+        None
+    }
+
+    /// Records that a peer was just seen at the given address. If its bucket is full, the least recently seen peer in it makes room.
+    pub fn insert(&mut self, key: PublicKey, addr: SocketAddr) {
+        let idx = match self.bucket_index(&key) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let k = self.k;
+        let bucket = &mut self.buckets[idx];
+        bucket.retain(|(other, _)| *other != key);
+        if bucket.len() >= k {
+            if let Some((evicted, _)) = bucket.pop_front() {
+                log::trace!("evicting {} from bucket {}", evicted, idx);
+            }
+        }
+        bucket.push_back((key, addr));
+    }
+
+    /// Forgets a peer.
+    pub fn remove(&mut self, key: &PublicKey) {
+        if let Some(idx) = self.bucket_index(key) {
+            self.buckets[idx].retain(|(other, _)| other != key);
+        }
+    }
+
+    /// Returns up to n known peers closest to the target, closest first.
+    pub fn closest(&self, target: &PublicKey, n: usize) -> Vec<(PublicKey, SocketAddr)> {
+        let mut all: Vec<(PublicKey, SocketAddr)> =
+            self.buckets.iter().flatten().copied().collect();
+        all.sort_unstable_by_key(|(key, _)| distance(key, target));
+        all.truncate(n);
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(first: u8, last: u8) -> PublicKey {
+        let mut key = [0; 32];
+        key[0] = first;
+        key[31] = last;
+        PublicKey(key)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn buckets_by_leading_zeros() {
+        let table = KBuckets::new(key(0, 0), 20);
+        assert_eq!(table.bucket_index(&key(0, 0)), None);
+        assert_eq!(table.bucket_index(&key(0x80, 0)), Some(0));
+        assert_eq!(table.bucket_index(&key(0x01, 0)), Some(7));
+        assert_eq!(table.bucket_index(&key(0, 0x01)), Some(255));
+        assert_eq!(table.bucket_index(&key(0, 0xff)), Some(248));
+    }
+
+    #[test]
+    fn closest_first() {
+        let mut table = KBuckets::new(key(0, 0), 20);
+        table.insert(key(0x80, 0), addr(1));
+        table.insert(key(0x01, 0), addr(2));
+        table.insert(key(0, 0x01), addr(3));
+        table.insert(key(0, 0), addr(4));
+        let closest: Vec<SocketAddr> = table
+            .closest(&key(0, 0x03), 2)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        assert_eq!(closest, vec![addr(3), addr(2)]);
+        let closest: Vec<SocketAddr> = table
+            .closest(&key(0xc0, 0), 10)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        assert_eq!(closest, vec![addr(1), addr(3), addr(2)]);
+    }
+
+    #[test]
+    fn full_buckets_evict_least_recently_seen() {
+        let mut table = KBuckets::new(key(0, 0), 2);
+        table.insert(key(0x80, 1), addr(1));
+        table.insert(key(0x80, 2), addr(2));
+        table.insert(key(0x80, 1), addr(1));
+        table.insert(key(0x80, 3), addr(3));
+        let mut kept: Vec<SocketAddr> = table
+            .closest(&key(0x80, 0), 10)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![addr(1), addr(3)]);
+    }
+}
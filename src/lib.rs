@@ -1,128 +1,348 @@
-//! Melnet serves as Themelio's peer-to-peer network layer, based on a randomized topology and gossip. Peers are divided into servers, which have a publicly reachable address, and clients, which do not. It's based on a simple stdcode request-response protocol, where the only way to "push" a message is to send a request to a server. There is no multiplexing --- the whole thing works like HTTP/1.1. TCP connections are pretty cheap these days.
+//! Melnet serves as Themelio's peer-to-peer network layer, based on a randomized topology and gossip. Peers are divided into servers, which have a publicly reachable address, and clients, which do not. It's based on a simple stdcode request-response protocol, where the only way to "push" a message is to send a request to a server. Requests carry an ID, so many requests can be in flight on one connection and responses come back in whatever order the server finishes them. Servers still understand the original, strictly in-order protocol, and clients fall back to it when talking to servers that predate request IDs.
 //!
-//! This also means that clients never receive notifications, and must poll servers.
+//! For messages that should reach the whole network, `NetState::broadcast` spreads a message by gossip, and `NetState::on_broadcast` handles messages on a topic. Each node handles and passes on each message at most once.
+//!
+//! Clients can also receive notifications without polling: a server declares topics with `NetState::add_topic` and pushes messages with `NetState::publish`, and clients subscribe with `Client::subscribe`. Messages come back over the client's existing connection, tagged with the ID of the subscription request.
 //!
 //! The general way to use `melnet` is as follows:
 //!
-//! 1. Create a `NetState`. This holds the routing table, RPC verb handlers, and other "global" data.
-//! 2. If running as a server, register RPC verbs with `NetState::register_verb` and run `NetState::run_server` in the background.
-//! 3. Use a `Client`, like the global one returned by `g_client()`, to make RPC calls to other servers. Servers are simply identified by a `std::net::SocketAddr`.
+//! 1. Create a `NetState`. This holds the routing table, RPC verb handlers, and other "global" data. Give it some seeds with `NetState::add_seed`, and it bootstraps its routing table from them whenever it runs low on routes.
+//! 2. If running as a server, register RPC verbs with `NetState::register_verb` and run `NetState::run_server` in the background. `NetState::stop` shuts it down gracefully.
+//! 3. Use `melnet::request`, which goes through a global `Client`, or a `Client` configured with `ClientBuilder`, to make RPC calls to other servers. Servers are simply identified by a `std::net::SocketAddr`.
+//!
+//! Cross-cutting logic, such as logging, access control or metrics, can wrap every verb with `NetState::add_middleware`, or particular verbs with `NetState::add_verb_middleware`. A few built-in verbs, for subscriptions, cancellation and address discovery, are handled before any middleware runs.
+//!
+//! A `NetState` can remember routes and reputations across restarts: give it a backend, such as `FilePersistence`, with `NetState::set_persistence`.
+//!
+//! Connections can optionally be encrypted and authenticated with a Noise handshake. Give a node a static key with `NetState::set_static_key`, and use `ClientBuilder::static_key` or `Client::pin` on the client side. A node pins the peers it talks to itself with `NetState::pin`. Peers that prove a static key are identified by it, so their routes and reputations follow them from address to address.
 
+mod bans;
 mod client;
+mod crypt;
+mod discovery;
 mod endpoint;
+mod framing;
+mod gossip;
+mod kademlia;
+mod limits;
+mod middleware;
+mod persist;
 mod pipeline;
 mod reptracker;
 mod routingtable;
+mod shutdown;
+mod transport;
 use anyhow::Context;
+use bans::BanList;
+pub use bans::{Ban, IpRange, ParseIpRangeError};
 use dashmap::DashMap;
 use derivative::*;
 pub use endpoint::*;
 use reptracker::RepTracker;
-use routingtable::*;
+pub use reptracker::{DefaultReputationPolicy, ReputationEvent, ReputationPolicy};
+use routingtable::RoutingTable;
+pub use routingtable::{AddressPolicy, Eviction, EvictionPolicy, EvictionReason, PeerId};
 use serde::{de::DeserializeOwned, Serialize};
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tap::TapFallible;
-mod reqs;
-use async_net::{TcpListener, TcpStream};
 mod common;
-pub use client::request;
+mod reqs;
+pub use client::{request, Client, ClientBuilder, RequestOptions, RetryPolicy, Subscription};
 pub use common::*;
+use crypt::{handshake_responder, FrameCodec, HANDSHAKE_MARKER};
+pub use crypt::{NodeKey, PublicKey};
+use discovery::{AddrVotes, OBSERVED_ADDR_VERB};
+use framing::{FrameLimits, FrameReservation};
+pub use gossip::GossipConfig;
+use gossip::{Gossip, GossipMsg, GOSSIP_VERB};
+use kademlia::{distance, KBuckets, FIND_NODE_VERB, LOOKUP_PARALLELISM};
+pub use limits::ServerLimits;
+use limits::{ip_peer, Limiter};
+pub use middleware::{Call, Middleware, Next};
 use parking_lot::{Mutex, RwLock};
-use rand::prelude::*;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+pub use persist::{FilePersistence, Persistence, Snapshot};
 use reqs::*;
-use smol::Timer;
-use smol::{prelude::*, Task};
+use shutdown::{RunningServer, Shutdown};
+use smol::io::WriteHalf;
+use smol::prelude::*;
+use smol::{Task, Timer};
 use smol_timeout::TimeoutExt;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+pub use transport::*;
 
-#[derive(Derivative, Clone, Default)]
-#[derivative(Debug)]
+#[derive(Derivative, Clone)]
+#[derivative(Debug, Default)]
 /// A clonable structure representing a melnet state. All copies share the same routing table.
 pub struct NetState {
     network_name: String,
     routes: Arc<RwLock<RoutingTable>>,
     #[derivative(Debug = "ignore")]
     verbs: Arc<DashMap<String, BoxedResponder>>,
+    // subscribers to each topic
+    #[derivative(Debug = "ignore")]
+    topics: Arc<DashMap<String, Vec<smol::channel::Sender<Vec<u8>>>>>,
+
+    // broadcast handlers and the IDs of recently seen broadcasts
+    #[derivative(Debug = "ignore")]
+    gossip: Arc<Gossip>,
+    gossip_config: GossipConfig,
 
     // reputations. Bad-reputation nodes get blacklisted
     #[derivative(Debug = "ignore")]
-    reputations: Arc<DashMap<SocketAddr, RepTracker>>,
+    reputations: Arc<DashMap<PeerId, RepTracker>>,
+    #[derivative(
+        Debug = "ignore",
+        Default(value = "Arc::new(DefaultReputationPolicy::default())")
+    )]
+    reputation_policy: Arc<dyn ReputationPolicy>,
+
+    // static key for the encrypted handshake, and the client we use to talk to other nodes
+    static_key: NodeKey,
+    require_encryption: bool,
+    #[derivative(Debug = "ignore")]
+    client: Arc<Client>,
+
+    // who wants to hear about routes being dropped
+    #[derivative(Debug = "ignore")]
+    eviction_observers: Arc<RwLock<Vec<EvictionObserver>>>,
+
+    // the optional Kademlia table
+    #[derivative(Debug = "ignore")]
+    kademlia: Option<Arc<RwLock<KBuckets>>>,
+
+    // which addresses we route to, and which addresses are our own
+    address_policy: AddressPolicy,
+    #[derivative(Debug = "ignore")]
+    own_addrs: Arc<RwLock<HashSet<SocketAddr>>>,
+    // what we listen on, what others see us as, and the public address we learned from that
+    #[derivative(Debug = "ignore")]
+    listen_addr: Arc<RwLock<Option<SocketAddr>>>,
+    #[derivative(Debug = "ignore")]
+    addr_votes: Arc<Mutex<AddrVotes>>,
+    #[derivative(Debug = "ignore")]
+    public_addr: Arc<RwLock<Option<SocketAddr>>>,
+    // nonces of pings in flight, and whether we received them ourselves
+    #[derivative(Debug = "ignore")]
+    self_probes: Arc<DashMap<u64, bool>>,
+
+    // where to bootstrap from, and how few routes we tolerate before doing so
+    seeds: Vec<String>,
+    #[derivative(Default(value = "8"))]
+    min_routes: usize,
+
+    // where routes and reputations are saved across restarts
+    #[derivative(Debug = "ignore")]
+    persistence: Option<Arc<dyn Persistence>>,
+
+    // address ranges we refuse to talk to
+    #[derivative(Debug = "ignore")]
+    bans: Arc<BanList>,
 
-    // Slot for the optional server task
-    _server_task: Arc<Mutex<Option<Task<()>>>>,
+    // connection and request limits for the server
+    #[derivative(Debug = "ignore")]
+    limiter: Arc<Limiter>,
+    // verbs that accept larger or smaller requests than the server's default
+    #[derivative(Debug = "ignore")]
+    verb_frame_sizes: Arc<DashMap<String, usize>>,
+
+    // middleware around every verb, and around particular verbs, outermost first
+    #[derivative(Debug = "ignore")]
+    middleware: Arc<RwLock<Vec<Arc<dyn Middleware>>>>,
+    #[derivative(Debug = "ignore")]
+    verb_middleware: Arc<DashMap<String, Vec<Arc<dyn Middleware>>>>,
+
+    // Slot for the optional server
+    #[derivative(Debug = "ignore")]
+    server: Arc<Mutex<Option<RunningServer>>>,
 }
 
 impl NetState {
     /// Starts the netstate in the background. This doesn't consume the netstate because the netstate struct can still be used to get out routes, register new verbs, etc even when it's concurrently run as a server.
-    pub fn start_server(&self, listener: TcpListener) {
+    pub fn start_server(&self, listener: impl Listener) {
         let mut this = self.clone();
         this.setup_routing();
-        // Spam neighbors with random routes
-
-        // Max number of connections
+        if let Some(addr) = listener.local_addr() {
+            self.add_own_addr(addr);
+            *self.listen_addr.write() = Some(addr);
+        }
         let this = self.clone();
-        let task = smolscale::spawn(async move {
+        let shutdown = Arc::new(Shutdown::default());
+        let conn_shutdown = shutdown.clone();
+        let accept_task = smolscale::spawn(async move {
             let _spammer = {
                 let this = this.clone();
-                smolscale::spawn(
-                    async move { this.new_addr_spam().race(this.get_routes_spam()).await },
-                )
+                smolscale::spawn(async move {
+                    this.new_addr_spam()
+                        .race(this.get_routes_spam())
+                        .race(this.persist_loop())
+                        .race(this.bootstrap_loop())
+                        .race(this.advertise_loop())
+                        .await
+                })
             };
             loop {
-                let (conn, addr) = listener.accept().await.unwrap();
-                // spawn a task, moving the sem_guard inside
+                let (conn, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    // the listener is gone for good, e.g. a closed in-memory one
+                    Err(err) if err.kind() == std::io::ErrorKind::NotConnected => {
+                        log::warn!("listener closed, no longer accepting: {:?}", err);
+                        break;
+                    }
+                    // usually running out of file descriptors, which may pass
+                    Err(err) => {
+                        log::warn!("could not accept connection: {:?}", err);
+                        Timer::after(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                if this.bans.is_banned(addr.ip()) {
+                    log::debug!("refusing connection from {}: banned", addr);
+                    continue;
+                }
+                let conn_guard = match this.limiter.admit(addr.ip()) {
+                    Some(guard) => guard,
+                    None => {
+                        log::debug!("refusing connection from {}: too many connections", addr);
+                        this.inbound_reputation_delta(
+                            addr,
+                            None,
+                            this.reputation_policy
+                                .delta(ReputationEvent::ConnectionRefused),
+                        );
+                        continue;
+                    }
+                };
+                // spawn a task, moving the guard inside
                 let this = this.clone();
+                let shutdown = conn_shutdown.clone();
                 smolscale::spawn(async move {
-                    if let Err(e) = this.server_handle(conn).await {
+                    let _conn_guard = conn_guard;
+                    let handle = this.server_handle(conn, addr, &shutdown);
+                    let killed = async {
+                        shutdown.killed().await;
+                        Ok(())
+                    };
+                    if let Err(e) = handle.or(killed).await {
                         log::trace!("{} terminating on error: {:?}", addr, e)
                     }
                 })
                 .detach();
             }
         });
-        *self._server_task.lock() = Some(task);
+        let old = self.server.lock().replace(RunningServer {
+            accept_task,
+            shutdown,
+        });
+        if let Some(old) = old {
+            smolscale::spawn(old.stop(Duration::from_secs(0))).detach();
+        }
+    }
+
+    /// Stops the server started by `start_server`, if any. Accepting connections, reading requests, and gossiping routes stop right away, while requests already being handled get until the deadline to finish before they're dropped. The server can be started again afterwards.
+    pub async fn stop(&self, deadline: Duration) {
+        let server = self.server.lock().take();
+        if let Some(server) = server {
+            server.stop(deadline).await;
+            if let Err(err) = self.save_snapshot().await {
+                log::warn!("could not save snapshot: {:?}", err)
+            }
+        }
+    }
+
+    /// Sets where routes and reputations are saved, and loads whatever was saved there before. Saved routes that went stale while the node was down are tried again before they count as routes. While the server runs, a snapshot is saved every minute and when the server stops.
+    pub fn set_persistence(&mut self, backend: impl Persistence) -> std::io::Result<()> {
+        if let Some(snapshot) = backend.load()? {
+            log::debug!(
+                "loaded {} routes and {} reputations",
+                snapshot.routes.len(),
+                snapshot.reputations.len()
+            );
+            for (id, reputation, last_update) in snapshot.reputations {
+                self.reputations
+                    .insert(id, RepTracker::from_parts(reputation, last_update));
+            }
+            let saved_routes = snapshot.routes;
+            self.update_routes(|routes| {
+                for (id, addr, last_seen) in saved_routes {
+                    routes.restore_route(id, addr, last_seen);
+                }
+            });
+        }
+        self.persistence = Some(Arc::new(backend));
+        Ok(())
+    }
+
+    /// Saves a snapshot of routes and reputations to the backend set with `NetState::set_persistence`, if any.
+    pub async fn save_snapshot(&self) -> std::io::Result<()> {
+        if let Some(backend) = self.persistence.clone() {
+            let snapshot = self.snapshot();
+            smol::unblock(move || backend.save(&snapshot)).await?;
+        }
+        Ok(())
+    }
+
+    /// Takes a snapshot of routes and reputations.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            routes: self.routes.read().to_vec(),
+            reputations: self
+                .reputations
+                .iter()
+                .map(|entry| {
+                    let (reputation, last_update) = entry.value().to_parts();
+                    (*entry.key(), reputation, last_update)
+                })
+                .collect(),
+        }
+    }
+
+    async fn persist_loop(&self) {
+        let mut tmr = Timer::interval(Duration::from_secs(60));
+        loop {
+            tmr.next().await;
+            if let Err(err) = self.save_snapshot().await {
+                log::warn!("could not save snapshot: {:?}", err)
+            }
+        }
     }
 
     #[deprecated]
-    pub async fn run_server(&self, listener: TcpListener) {
+    pub async fn run_server(&self, listener: impl Listener) {
         self.start_server(listener);
         smol::future::pending().await
     }
 
     /// Random spammer
     async fn new_addr_spam(&self) {
-        let mut rng = rand::rngs::OsRng {};
         let mut tmr = Timer::interval(Duration::from_secs(1));
         loop {
             tmr.next().await;
-            let routes = self.routes.read().to_vec();
-            if !routes.is_empty() {
-                let (rand_neigh, _) = routes[rng.gen::<usize>() % routes.len()];
-                let (rand_route, _) = routes[rng.gen::<usize>() % routes.len()];
-                if rand_neigh == rand_route {
-                    continue;
-                }
+            // routes() leaves out banned and disreputable peers, and its first few come from different subnets where possible
+            if let [rand_neigh, rand_route, ..] = self.routes()[..] {
                 let network_name = self.network_name.clone();
                 log::debug!("sending new_addr {} to {}", rand_neigh, rand_route);
-                let reputations = self.reputations.clone();
+                let this = self.clone();
+                let client = self.client.clone();
                 smolscale::spawn(async move {
-                    let _ = crate::request::<RoutingRequest, String>(
-                        rand_neigh,
-                        &network_name,
-                        "new_addr",
-                        RoutingRequest {
-                            proto: String::from("tcp"),
-                            addr: rand_route.to_string(),
-                        },
-                    )
-                    .await
-                    .tap_err(|err| {
-                        reputations.entry(rand_neigh).or_default().delta(-3.0);
-                        log::debug!("addrspam failed to {} ({:?})", rand_neigh, err);
-                    });
+                    let _ = client
+                        .request_with::<RoutingRequest, String>(
+                            rand_neigh,
+                            &network_name,
+                            "new_addr",
+                            RoutingRequest {
+                                proto: String::from("tcp"),
+                                addr: rand_route.to_string(),
+                            },
+                            RequestOptions::default().timeout(Duration::from_secs(60)),
+                        )
+                        .await
+                        .map_err(anyhow::Error::from)
+                        .tap_err(|err| {
+                            this.reputation_event(rand_neigh, ReputationEvent::NewAddrFailed);
+                            log::debug!("addrspam failed to {} ({:?})", rand_neigh, err);
+                        });
                 })
                 .detach();
             }
@@ -133,44 +353,289 @@ impl NetState {
     async fn get_routes_spam(&self) {
         let mut tmr = Timer::interval(Duration::from_secs(30));
         loop {
-            if let Some(route) = self.routes().get(0).copied() {
-                let network_name = self.network_name.clone();
-                let state = self.clone();
-                let reputations = self.reputations.clone();
-                smolscale::spawn(async move {
-                    reputations.entry(route).or_default().delta(-1.0);
-                    let resp: Vec<SocketAddr> = crate::request::<(), Vec<SocketAddr>>(
-                        route,
-                        &network_name,
-                        "get_routes",
+            // routes() is shuffled, so this asks a few random neighbors
+            for route in self.routes().into_iter().take(3) {
+                let this = self.clone();
+                smolscale::spawn(async move { this.get_routes_from(route).await }).detach();
+            }
+            tmr.next().await;
+        }
+    }
+
+    /// Asks a node for its routes, and checks out every route it returns.
+    async fn get_routes_from(&self, route: SocketAddr) -> anyhow::Result<()> {
+        let resp: Vec<SocketAddr> = self
+            .client
+            .request_with::<(), Vec<SocketAddr>>(
+                route,
+                &self.network_name,
+                "get_routes",
+                (),
+                RequestOptions::default().timeout(Duration::from_secs(10)),
+            )
+            .await
+            .map_err(anyhow::Error::from)
+            .tap_err(|err| {
+                self.reputation_event(route, ReputationEvent::GetRoutesFailed);
+                log::debug!("could not get routes from {}: {:?}", route, err)
+            })?;
+        log::debug!("{} routes from {}: {:?}", resp.len(), route, resp);
+        for new_route in resp {
+            self.handle_new_route(new_route)
+        }
+        Ok(())
+    }
+
+    /// Asks neighbors what address they see us at, and once most of them agree, advertises our public address to them.
+    async fn advertise_loop(&self) {
+        let mut tmr = Timer::interval(Duration::from_secs(120));
+        loop {
+            let neighs: Vec<SocketAddr> = self.routes().into_iter().take(4).collect();
+            let observations = neighs.iter().map(|&neigh| async move {
+                let res = self
+                    .client
+                    .request_with::<(), SocketAddr>(
+                        neigh,
+                        &self.network_name,
+                        OBSERVED_ADDR_VERB,
                         (),
+                        RequestOptions::default().timeout(Duration::from_secs(10)),
                     )
-                    .timeout(Duration::from_secs(10))
                     .await
-                    .context("timeout")
-                    .tap_err(|err| {
-                        log::debug!("could not get routes from {}: {:?}", route, err)
-                    })??;
-                    log::debug!("{} routes from {}: {:?}", resp.len(), route, resp);
-                    for new_route in resp {
-                        state.handle_new_route(new_route)
+                    .map_err(anyhow::Error::from);
+                (neigh, res)
+            });
+            for (neigh, res) in futures_util::future::join_all(observations).await {
+                match res {
+                    Ok(observed) => {
+                        let voter = self.peer_id(neigh);
+                        self.addr_votes.lock().vote(voter, observed.ip());
                     }
-                    reputations.entry(route).or_default().delta(1.0);
-                    Ok::<_, anyhow::Error>(())
-                })
-                .detach();
+                    Err(err) => {
+                        log::debug!("could not get observed address from {}: {:?}", neigh, err)
+                    }
+                }
+            }
+            if let Some(public_addr) = self.learn_public_addr() {
+                for neigh in neighs {
+                    let client = self.client.clone();
+                    let network_name = self.network_name.clone();
+                    smolscale::spawn(async move {
+                        let res = client
+                            .request_with::<RoutingRequest, String>(
+                                neigh,
+                                &network_name,
+                                "new_addr",
+                                RoutingRequest {
+                                    proto: String::from("tcp"),
+                                    addr: public_addr.to_string(),
+                                },
+                                RequestOptions::default().timeout(Duration::from_secs(60)),
+                            )
+                            .await
+                            .map_err(anyhow::Error::from);
+                        if let Err(err) = res {
+                            log::debug!("could not advertise ourselves to {}: {:?}", neigh, err)
+                        }
+                    })
+                    .detach();
+                }
+            }
+            tmr.next().await;
+        }
+    }
+
+    /// Works out our public address from the IP most neighbors see us at and the port we listen on.
+    fn learn_public_addr(&self) -> Option<SocketAddr> {
+        let ip = self.addr_votes.lock().winner()?;
+        let port = self.listen_addr.read().as_ref()?.port();
+        let addr = SocketAddr::new(ip, port);
+        if !self.address_policy.allows(&addr) {
+            return None;
+        }
+        if self.public_addr.write().replace(addr) != Some(addr) {
+            log::info!("learned public address {}", addr);
+            self.add_own_addr(addr);
+        }
+        Some(addr)
+    }
+
+    /// Gets the public address this server learned from its neighbors, if it has learned one yet. This is what it advertises to them.
+    pub fn public_addr(&self) -> Option<SocketAddr> {
+        *self.public_addr.read()
+    }
+
+    /// Re-bootstraps from the seeds whenever there are too few routes.
+    async fn bootstrap_loop(&self) {
+        let mut tmr = Timer::interval(Duration::from_secs(30));
+        loop {
+            let missing = self.min_routes.saturating_sub(self.routes().len());
+            if missing > 0 {
+                // addresses we heard about earlier are worth another try
+                for addr in self.routes.read().new_candidates(missing) {
+                    self.try_route(addr);
+                }
+                self.bootstrap().await;
             }
             tmr.next().await;
         }
     }
 
+    /// Resolves every seed, adds it as a route, and asks it for more routes. A server does this by itself whenever it has fewer than `NetState::set_min_routes` routes, but clients have to call it themselves.
+    pub async fn bootstrap(&self) {
+        log::debug!("bootstrapping from {} seeds", self.seeds.len());
+        let queries = self.seeds.iter().map(|seed| async move {
+            let addrs = smol::net::resolve(seed.as_str())
+                .await
+                .tap_err(|err| log::warn!("could not resolve seed {}: {:?}", seed, err))
+                .unwrap_or_default();
+            for addr in addrs {
+                if self.get_routes_from(addr).await.is_ok() {
+                    self.add_route(addr);
+                }
+            }
+        });
+        futures_util::future::join_all(queries).await;
+    }
+
+    /// Adds a seed to bootstrap from. Seeds are `host:port` strings, where the host is either an IP address or a DNS name, which is resolved locally every time the node bootstraps.
+    pub fn add_seed(&mut self, seed: &str) {
+        self.seeds.push(seed.to_owned());
+    }
+
+    /// Sets which addresses the node routes to. The default only allows publicly routable addresses; LAN and test networks have to allow private or loopback addresses explicitly. Addresses configured through `NetState::add_route` and `NetState::add_seed` are always allowed.
+    pub fn set_address_policy(&mut self, policy: AddressPolicy) {
+        self.address_policy = policy;
+    }
+
+    /// Marks an address as one of our own, so that we never route to it. Servers mark the address they listen on by themselves, and discover others when they find themselves answering their own pings.
+    pub fn add_own_addr(&self, addr: SocketAddr) {
+        if self.own_addrs.write().insert(addr) {
+            log::debug!("learned own address {}", addr);
+        }
+    }
+
+    /// Whether we'd route to the address: it must be allowed by the address policy, not be one of our own, and not be banned.
+    fn address_allowed(&self, addr: &SocketAddr) -> bool {
+        self.address_policy.allows(addr)
+            && !self.own_addrs.read().contains(addr)
+            && !self.bans.is_banned(addr.ip())
+    }
+
+    /// Bans a range of addresses, for the given duration or until it's lifted with `NetState::unban`. The server refuses new connections from banned addresses, and the node stops routing to them. Connections that are already open stay open.
+    pub fn ban(&self, range: impl Into<IpRange>, duration: Option<Duration>) {
+        let range = range.into();
+        log::debug!("banning {} for {:?}", range, duration);
+        self.bans.ban(range, duration);
+    }
+
+    /// Lifts the ban on a range early, returning whether there was one. The range has to match the banned range exactly.
+    pub fn unban(&self, range: impl Into<IpRange>) -> bool {
+        self.bans.unban(&range.into())
+    }
+
+    /// Lists the bans in force.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.bans()
+    }
+
+    /// Whether the address is banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.is_banned(ip)
+    }
+
+    /// Sets limits on connections and request rates for the server. This takes effect the next time the server starts.
+    pub fn set_server_limits(&mut self, limits: ServerLimits) {
+        self.limiter = Arc::new(Limiter::new(limits));
+    }
+
+    /// Sets the largest request a verb accepts, in place of `ServerLimits::max_frame_size`. This can go either way: a verb that fetches small things can refuse big requests, and a verb that uploads big things can allow them.
+    pub fn set_verb_max_frame_size(&self, verb: &str, max_size: usize) {
+        self.verb_frame_sizes.insert(verb.into(), max_size);
+    }
+
+    /// The largest request the verb accepts.
+    fn max_frame_size(&self, verb: &str) -> usize {
+        self.verb_frame_sizes
+            .get(verb)
+            .map(|size| *size)
+            .unwrap_or_else(|| self.limiter.max_frame_size())
+    }
+
+    /// The limits for reading the next request frame, which might be for any verb.
+    fn frame_limits(&self) -> FrameLimits {
+        let max_size = self
+            .verb_frame_sizes
+            .iter()
+            .map(|size| *size.value())
+            .fold(self.limiter.max_frame_size(), usize::max);
+        self.limiter.frame_limits(max_size)
+    }
+
+    /// Sets the most addresses from one subnet (an IPv4 /16 or IPv6 /32) that the routing table keeps, both among routes that answered us and among addresses we've only heard about. This stops any one party from filling the routing table by controlling lots of nearby addresses. The defaults are 8 and 32.
+    pub fn set_subnet_quotas(&mut self, tried: usize, new: usize) {
+        self.update_routes(|routes| routes.set_subnet_quotas(tried, new));
+    }
+
+    /// Sets the most addresses the routing table keeps, among routes that answered us and among addresses we've only heard about. The defaults are 1024 and 4096.
+    pub fn set_route_capacity(&mut self, tried: usize, new: usize) {
+        self.update_routes(|routes| routes.set_capacity(tried, new));
+    }
+
+    /// Sets how the routing table picks which routes to drop once it's full.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.update_routes(|routes| routes.set_eviction_policy(policy));
+    }
+
+    /// Registers a callback that hears about every route dropped from the routing table, whether it expired, its subnet was full, or the table was full.
+    pub fn on_eviction(&self, observer: impl Fn(&Eviction) + Send + Sync + 'static) {
+        self.eviction_observers.write().push(Arc::new(observer));
+    }
+
+    /// Changes the routing table, then drops whatever no longer fits and reports everything dropped.
+    fn update_routes<T>(&self, f: impl FnOnce(&mut RoutingTable) -> T) -> T {
+        let (res, evicted) = {
+            let mut routes = self.routes.write();
+            let res = f(&mut routes);
+            routes.enforce_capacity(|id| {
+                self.reputations
+                    .get(&id)
+                    .map(|r| r.get_reputation(&*self.reputation_policy))
+                    .unwrap_or_default()
+            });
+            (res, routes.take_evictions())
+        };
+        if !evicted.is_empty() {
+            let observers = self.eviction_observers.read().clone();
+            for eviction in evicted {
+                log::debug!(
+                    "evicted {} ({:?}) from the routing table",
+                    eviction.addr,
+                    eviction.reason
+                );
+                for observer in observers.iter() {
+                    observer(&eviction);
+                }
+            }
+        }
+        res
+    }
+
+    /// Sets how many routes the node tries to keep. Whenever it has fewer, it re-bootstraps from its seeds. The default is 8.
+    pub fn set_min_routes(&mut self, min_routes: usize) {
+        self.min_routes = min_routes;
+    }
+
     fn handle_new_route(&self, new_route: SocketAddr) {
+        if !self.address_allowed(&new_route) {
+            log::debug!("rejecting disallowed address {}", new_route);
+            return;
+        }
+        // misbehaving clients lose reputation by IP, and shouldn't get routed to once they serve from there
         let rep = self
-            .reputations
-            .entry(new_route)
-            .or_default()
-            .get_reputation();
-        if rep < -5.0 {
+            .reputation_of(new_route)
+            .min(self.reputation(ip_peer(new_route.ip())));
+        if !self.reputation_policy.is_routable(rep) {
             log::warn!("rejecting {} due to low reputation {:.1}", new_route, rep);
             return;
         }
@@ -181,39 +646,125 @@ impl NetState {
         };
         if must_refresh {
             log::debug!("NEW route {} from ", new_route);
-            let this = self.clone();
-            let reputations = self.reputations.clone();
-            smolscale::spawn(async move {
-                reputations.entry(new_route).or_default().delta(-1.0);
-                crate::request::<_, u64>(new_route, &this.network_name, "ping", 10)
-                    .timeout(Duration::from_secs(3))
-                    .await
-                    .context("timeout")
-                    .tap_err(|err| {
-                        log::warn!("route {} was unpingable ({:?})!", new_route, err)
-                    })??;
-                reputations.entry(new_route).or_default().delta(2.0);
-                this.add_route(new_route);
-                Ok::<_, anyhow::Error>(())
-            })
-            .detach();
+            self.update_routes(|routes| routes.add_new(new_route));
+            self.try_route(new_route);
         }
     }
 
-    async fn server_handle(&self, mut conn: TcpStream) -> anyhow::Result<()> {
-        conn.set_nodelay(true)?;
-        loop {
-            match self
-                .server_handle_one(&mut conn)
+    /// Pings an address in the background, moving it from the new table to the tried table if it answers.
+    fn try_route(&self, new_route: SocketAddr) {
+        let this = self.clone();
+        smolscale::spawn(async move {
+            this.reputation_event(new_route, ReputationEvent::PingFailed);
+            let nonce: u64 = rand::random();
+            this.self_probes.insert(nonce, false);
+            let res = this
+                .client
+                .request_with::<_, u64>(
+                    new_route,
+                    &this.network_name,
+                    "ping",
+                    nonce,
+                    RequestOptions::default().timeout(Duration::from_secs(3)),
+                )
+                .await
+                .map_err(anyhow::Error::from);
+            let pinged_self = this.self_probes.remove(&nonce).map(|(_, v)| v) == Some(true);
+            if let Err(err) = res {
+                log::warn!("route {} was unpingable ({:?})!", new_route, err);
+                this.routes.write().remove_new(new_route);
+                return Err(err);
+            }
+            if pinged_self || this.peer_id(new_route) == PeerId::Key(this.public_key()) {
+                log::debug!("{} is our own address", new_route);
+                this.routes.write().remove_new(new_route);
+                this.add_own_addr(new_route);
+                return Ok(());
+            }
+            // the ping might have taught us who's at this address, so we add the route first
+            this.add_route(new_route);
+            this.reputation_event(new_route, ReputationEvent::PingAnswered);
+            Ok::<_, anyhow::Error>(())
+        })
+        .detach();
+    }
+
+    async fn server_handle(
+        &self,
+        mut conn: BoxedConnection,
+        peer_addr: SocketAddr,
+        shutdown: &Arc<Shutdown>,
+    ) -> anyhow::Result<()> {
+        // the first frame tells us whether the connection is encrypted
+        let limits = self.frame_limits();
+        let (first, first_reservation) = FrameCodec::default()
+            .read(&mut conn, &limits)
+            .timeout(Duration::from_secs(60))
+            .await
+            .context("timeout")??;
+        let (codec, remote_key, mut first) = if first.first() == Some(&HANDSHAKE_MARKER) {
+            drop(first_reservation);
+            let session = handshake_responder(&mut conn, &self.static_key, &first, &limits)
                 .timeout(Duration::from_secs(60))
                 .await
-            {
+                .context("timeout")??;
+            log::trace!(
+                "encrypted connection from {} with key {}",
+                peer_addr,
+                session.remote_key()
+            );
+            let remote_key = session.remote_key();
+            (FrameCodec::encrypted(session), Some(remote_key), None)
+        } else if self.require_encryption {
+            anyhow::bail!("refusing plaintext connection from {}", peer_addr)
+        } else {
+            (
+                FrameCodec::default(),
+                None,
+                Some((first, first_reservation)),
+            )
+        };
+        let (mut reader, writer) = smol::io::split(conn);
+        let conn = ServerConn {
+            peer_addr,
+            remote_key,
+            writer: Arc::new(smol::lock::Mutex::new(writer)),
+            codec,
+            shutdown: shutdown.clone(),
+            in_flight: Default::default(),
+        };
+        // subscriptions live exactly as long as the connection
+        let subscriptions = DashMap::new();
+        loop {
+            let handle_one = async {
+                let (frame, reservation) = match first.take() {
+                    Some(first) => first,
+                    None => {
+                        let draining = async {
+                            shutdown.draining().await;
+                            Err(MelnetError::Network(std::io::Error::new(
+                                std::io::ErrorKind::ConnectionAborted,
+                                "server shutting down",
+                            )))
+                        };
+                        conn.codec
+                            .read(&mut reader, &self.frame_limits())
+                            .or(draining)
+                            .await?
+                    }
+                };
+                self.server_handle_one(frame, reservation, SystemTime::now(), &conn, &subscriptions)
+                    .await
+            };
+            // idle connections are fine as long as something is subscribed
+            let handled = if subscriptions.is_empty() {
+                handle_one.timeout(Duration::from_secs(60)).await
+            } else {
+                Some(handle_one.await)
+            };
+            match handled {
                 Some(Err(err)) => {
-                    log::trace!(
-                        "connection from {:?} died in error {:?}",
-                        conn.peer_addr(),
-                        err
-                    );
+                    log::trace!("connection from {:?} died in error {:?}", peer_addr, err);
                     return Err(err);
                 }
                 Some(Ok(_)) => {}
@@ -222,81 +773,272 @@ impl NetState {
         }
     }
 
-    async fn server_handle_one(&self, conn: &mut TcpStream) -> anyhow::Result<()> {
-        // read command
-        let cmd: RawRequest = stdcode::deserialize(&read_len_bts(conn.clone()).await?)?;
-        if cmd.proto_ver != 1 {
-            let err = stdcode::serialize(&RawResponse {
-                kind: "Err".to_owned(),
-                body: stdcode::serialize(&"bad protocol version").unwrap(),
-            })
-            .unwrap();
-            write_len_bts(conn, &err).await?;
-            return Err(anyhow::anyhow!("bad"));
-        }
-        if cmd.netname != self.network_name {
-            return Err(anyhow::anyhow!("bad"));
-        }
-        log::trace!("got command {:?} from {:?}", cmd.verb, conn.peer_addr());
-        // respond to command
-        let response_fut = {
-            let responder = self.verbs.get(&cmd.verb);
-            if let Some(responder) = responder {
-                let res = responder.0(&cmd.payload);
-                Some(res)
-            } else {
-                None
+    /// Handles one request frame. The frame's memory reservation is given back once the request has been handled, even if that happens in the background.
+    async fn server_handle_one(
+        &self,
+        frame: Vec<u8>,
+        reservation: FrameReservation,
+        received: SystemTime,
+        conn: &ServerConn,
+        subscriptions: &DashMap<u64, Task<()>>,
+    ) -> anyhow::Result<()> {
+        match frame.first().copied() {
+            Some(LEGACY_PROTO_VER) => {
+                let cmd: LegacyRawRequest = stdcode::deserialize(&frame)?;
+                if cmd.netname != self.network_name {
+                    return Err(anyhow::anyhow!("bad"));
+                }
+                log::trace!("got command {:?} from {:?}", cmd.verb, conn.peer_addr);
+                let _guard = conn.shutdown.track();
+                // legacy clients match responses by order, so we respond before reading anything else
+                let result = match self
+                    .rate_limit(conn)
+                    .and_then(|_| self.check_frame_size(&cmd.verb, frame.len()))
+                {
+                    Ok(()) => {
+                        self.respond(Call {
+                            verb: cmd.verb,
+                            id: None,
+                            body: cmd.payload,
+                            meta: conn.meta(LEGACY_PROTO_VER, received, None),
+                        })
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
+                let response = stdcode::serialize(&LegacyRawResponse::from_result(result)).unwrap();
+                conn.write(&response).await?;
             }
-        };
-        let response: Result<Vec<u8>> = if let Some(fut) = response_fut {
-            fut.await
+            Some(PROTO_VER) => {
+                let cmd: RawRequest = stdcode::deserialize(&frame)?;
+                if cmd.netname != self.network_name {
+                    return Err(anyhow::anyhow!("bad"));
+                }
+                log::trace!(
+                    "got command {:?} (id {}) from {:?}",
+                    cmd.verb,
+                    cmd.id,
+                    conn.peer_addr
+                );
+                // budgets too big to add up to an instant are as good as no budget at all
+                let deadline = cmd
+                    .budget
+                    .and_then(|budget| Instant::now().checked_add(budget));
+                if let Err(err) = self
+                    .rate_limit(conn)
+                    .and_then(|_| self.check_frame_size(&cmd.verb, frame.len()))
+                {
+                    let response = RawResponse {
+                        id: cmd.id,
+                        result: Err(err),
+                    };
+                    return conn.write(&stdcode::serialize(&response).unwrap()).await;
+                }
+                match cmd.verb.as_str() {
+                    SUBSCRIBE_VERB => self.handle_subscribe(cmd, conn, subscriptions).await?,
+                    OBSERVED_ADDR_VERB => {
+                        let response = RawResponse {
+                            id: cmd.id,
+                            result: Ok(stdcode::serialize(&conn.peer_addr).unwrap()),
+                        };
+                        conn.write(&stdcode::serialize(&response).unwrap()).await?;
+                    }
+                    CANCEL_VERB => {
+                        if let Ok(id) = stdcode::deserialize::<u64>(&cmd.payload) {
+                            if conn.in_flight.remove(&id).is_some() {
+                                log::trace!("request {} cancelled by {:?}", id, conn.peer_addr);
+                            }
+                        }
+                    }
+                    UNSUBSCRIBE_VERB => {
+                        let result = stdcode::deserialize::<u64>(&cmd.payload)
+                            .map(|id| {
+                                subscriptions.remove(&id);
+                                stdcode::serialize(&()).unwrap()
+                            })
+                            .map_err(|e| RemoteError::new(ErrorCode::BadRequest, e));
+                        let response =
+                            stdcode::serialize(&RawResponse { id: cmd.id, result }).unwrap();
+                        conn.write(&response).await?;
+                    }
+                    _ => {
+                        // responses carry the request ID, so we respond in the background and move on to the next request
+                        let this = self.clone();
+                        let shutdown = conn.shutdown.clone();
+                        let conn = conn.clone();
+                        let guard = shutdown.track();
+                        // the client cancels the request by making us drop the sender
+                        let (send_cancel, recv_cancel) = smol::channel::bounded::<()>(1);
+                        conn.in_flight.insert(cmd.id, send_cancel);
+                        let respond = async move {
+                            let _guard = guard;
+                            // the request still takes up memory until its handler is done with it
+                            let _reservation = reservation;
+                            let call = Call {
+                                verb: cmd.verb,
+                                id: Some(cmd.id),
+                                body: cmd.payload,
+                                meta: conn.meta(PROTO_VER, received, deadline),
+                            };
+                            // only the handler gets cancelled, never a half-written response
+                            let result = async { Some(this.respond(call).await) }
+                                .or(async {
+                                    let _ = recv_cancel.recv().await;
+                                    None
+                                })
+                                .await;
+                            conn.in_flight.remove(&cmd.id);
+                            let result = match result {
+                                Some(result) => result,
+                                None => return,
+                            };
+                            let response =
+                                stdcode::serialize(&RawResponse { id: cmd.id, result }).unwrap();
+                            if let Err(err) = conn.write(&response).await {
+                                log::trace!("could not respond to request {}: {:?}", cmd.id, err)
+                            }
+                        };
+                        smolscale::spawn(respond.or(async move { shutdown.killed().await }))
+                            .detach();
+                    }
+                }
+            }
+            _ => {
+                // every client understands legacy responses, so that's how we complain
+                let err = stdcode::serialize(&LegacyRawResponse {
+                    kind: "Err".to_owned(),
+                    body: stdcode::serialize(&"bad protocol version").unwrap(),
+                })
+                .unwrap();
+                conn.write(&err).await?;
+                return Err(anyhow::anyhow!("bad"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a token from the peer's request bucket. Peers that run out lose reputation, and have their request refused.
+    fn rate_limit(&self, conn: &ServerConn) -> std::result::Result<(), RemoteError> {
+        if self.limiter.allow_request(conn.peer_addr.ip()) {
+            Ok(())
         } else {
-            Err(MelnetError::VerbNotFound)
+            log::debug!("rate limiting {}", conn.peer_addr);
+            self.inbound_reputation_delta(
+                conn.peer_addr,
+                conn.remote_key,
+                self.reputation_policy.delta(ReputationEvent::RateLimited),
+            );
+            Err(RemoteError::new(ErrorCode::Overloaded, "rate limited"))
+        }
+    }
+
+    /// Refuses requests that are bigger than their verb allows. The frame has already been read by then, but only up to the largest size any verb allows.
+    fn check_frame_size(&self, verb: &str, len: usize) -> std::result::Result<(), RemoteError> {
+        if len > self.max_frame_size(verb) {
+            Err(RemoteError::new(ErrorCode::BadRequest, "request too large"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Changes the reputation of whoever connected to us from the given address. That's the static key they proved if they did. Otherwise it's everyone at the same IP, since clients connect from ephemeral ports: every route there, and the identity the IP itself has, which counts even for clients that aren't routes.
+    pub(crate) fn inbound_reputation_delta(
+        &self,
+        peer_addr: SocketAddr,
+        remote_key: Option<PublicKey>,
+        delta: f64,
+    ) {
+        let ids: HashSet<PeerId> = match remote_key {
+            Some(key) => std::iter::once(PeerId::Key(key)).collect(),
+            None => self
+                .routes
+                .read()
+                .to_vec()
+                .into_iter()
+                .filter(|(_, addr, _)| addr.ip() == peer_addr.ip())
+                .map(|(id, _, _)| id)
+                .chain(std::iter::once(ip_peer(peer_addr.ip())))
+                .collect(),
         };
-        match response {
-            Ok(resp) => {
-                write_len_bts(
-                    conn,
-                    &stdcode::serialize(&RawResponse {
-                        kind: "Ok".into(),
-                        body: resp,
-                    })
-                    .unwrap(),
-                )
-                .await?
-            }
-            Err(MelnetError::Custom(string)) => {
-                write_len_bts(
-                    conn,
-                    &stdcode::serialize(&RawResponse {
-                        kind: "Err".into(),
-                        body: string.as_bytes().into(),
-                    })
-                    .unwrap(),
-                )
-                .await?
+        for id in ids {
+            self.reputation_change(id, delta);
+        }
+    }
+
+    /// Starts a subscription for the client, pushing everything published to the topic back with the request's ID until the client unsubscribes or the connection closes.
+    async fn handle_subscribe(
+        &self,
+        cmd: RawRequest,
+        conn: &ServerConn,
+        subscriptions: &DashMap<u64, Task<()>>,
+    ) -> anyhow::Result<()> {
+        let recv = stdcode::deserialize::<String>(&cmd.payload)
+            .map_err(|e| RemoteError::new(ErrorCode::BadRequest, e))
+            .and_then(|topic| {
+                let mut subscribers = self.topics.get_mut(&topic).ok_or_else(|| {
+                    RemoteError::new(ErrorCode::NotFound, format!("no such topic {:?}", topic))
+                })?;
+                let (send, recv) = smol::channel::bounded(64);
+                subscribers.push(send);
+                Ok(recv)
+            });
+        let recv = match recv {
+            Ok(recv) => recv,
+            Err(err) => {
+                let response = RawResponse {
+                    id: cmd.id,
+                    result: Err(err),
+                };
+                return conn.write(&stdcode::serialize(&response).unwrap()).await;
             }
-            Err(MelnetError::VerbNotFound) => {
-                write_len_bts(
-                    conn,
-                    &stdcode::serialize(&RawResponse {
-                        kind: "NoVerb".into(),
-                        body: b"".to_vec(),
-                    })
-                    .unwrap(),
-                )
-                .await?
+        };
+        let ack = RawResponse {
+            id: cmd.id,
+            result: Ok(vec![]),
+        };
+        conn.write(&stdcode::serialize(&ack).unwrap()).await?;
+        let conn = conn.clone();
+        let id = cmd.id;
+        let forward = smolscale::spawn(async move {
+            while let Ok(msg) = recv.recv().await {
+                let response = RawResponse {
+                    id,
+                    result: Ok(msg),
+                };
+                if conn
+                    .write(&stdcode::serialize(&response).unwrap())
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
             }
-            err => {
-                log::error!(
-                    "bad error created by responder at verb {}: {:?}",
-                    cmd.verb,
-                    err
-                );
-                anyhow::bail!("wtf")
+        });
+        subscriptions.insert(id, forward);
+        Ok(())
+    }
+
+    /// Runs a call through the middleware and on to its verb's handler, giving up once the call's deadline passes.
+    async fn respond(&self, call: Call) -> std::result::Result<Vec<u8>, RemoteError> {
+        let responder = self.verbs.get(&call.verb).map(|r| r.clone());
+        let mut stack = self.middleware.read().clone();
+        if let Some(verb_middleware) = self.verb_middleware.get(&call.verb) {
+            stack.extend(verb_middleware.iter().cloned());
+        }
+        let deadline = call.meta.deadline;
+        let response = Next::new(stack, responder).run(call);
+        match deadline {
+            // the deadline goes first, so that a call that's already too late never starts
+            Some(deadline) => {
+                async {
+                    Timer::at(deadline).await;
+                    Err(RemoteError::new(ErrorCode::Timeout, "deadline exceeded"))
+                }
+                .or(response)
+                .await
             }
+            None => response.await,
         }
-        Ok(())
     }
 
     /// Registers the handler for new_peer.
@@ -304,6 +1046,10 @@ impl NetState {
         // ping just responds to a u64 with itself
         self.listen("ping", |ping: Request<u64>| async move {
             let body = ping.body;
+            // if we sent this ping, we pinged ourselves
+            if let Some(mut probe) = ping.state.self_probes.get_mut(&body) {
+                *probe = true;
+            }
             Ok(body)
         });
         // new_addr adds a new address
@@ -312,15 +1058,47 @@ impl NetState {
             let state = request.state.clone();
             if rr.proto != "tcp" {
                 log::debug!("new_addr saw unrecognizable protocol = {:?}", rr.proto);
-                anyhow::bail!("bad protocol")
+                return Err(RemoteError::new(ErrorCode::BadRequest, "bad protocol").into());
             }
-            state.handle_new_route(request.body.addr.parse()?);
+            let addr = request
+                .body
+                .addr
+                .parse()
+                .map_err(|e| RemoteError::new(ErrorCode::BadRequest, e))?;
+            // honest peers with other address policies send addresses we don't route to, so those are ignored rather than refused
+            state.handle_new_route(addr);
             Ok("".to_string())
         });
         // get_routes dumps out a slice of known routes
         self.listen("get_routes", |request: Request<()>| async move {
             Ok(request.state.routes())
-        })
+        });
+        // find_node returns the peers we know closest to a key
+        if let Some(kademlia) = self.kademlia.clone() {
+            self.listen(FIND_NODE_VERB, move |request: Request<PublicKey>| {
+                let kademlia = kademlia.clone();
+                async move {
+                    let kademlia = kademlia.read();
+                    Ok(kademlia.closest(&request.body, kademlia.k()))
+                }
+            });
+        }
+        // gossip_msg handles a broadcast the first time we see it, then passes it on
+        self.listen(GOSSIP_VERB, |request: Request<GossipMsg>| async move {
+            let msg = request.body;
+            let state = request.state;
+            if state.gossip.see(msg.id, state.gossip_config.seen_capacity) {
+                let handler = state.gossip.handlers.get(&msg.topic).map(|h| h.clone());
+                let relay = handler.map(|h| h(&msg.body)).unwrap_or(true);
+                if relay && msg.ttl > 1 {
+                    state.gossip_forward(GossipMsg {
+                        ttl: msg.ttl - 1,
+                        ..msg
+                    });
+                }
+            }
+            Ok(())
+        });
     }
 
     /// Registers a verb.
@@ -337,9 +1115,265 @@ impl NetState {
         self.verbs.insert(verb.into(), responder);
     }
 
-    /// Adds a route to the routing table.
+    /// Wraps every verb, including ones registered later, in a middleware. Middleware added first runs outermost, and all middleware added this way runs outside any added with `NetState::add_verb_middleware`. The built-in `subscribe`, `unsubscribe`, `observed_addr` and `cancel` verbs are handled before middleware gets to see them, so access control done in middleware doesn't cover subscriptions or address discovery.
+    pub fn add_middleware(&self, middleware: impl Middleware) {
+        self.middleware.write().push(Arc::new(middleware));
+    }
+
+    /// Wraps one verb in a middleware. Middleware added first runs outermost. Middleware on the built-in verbs listed under `NetState::add_middleware` never runs.
+    pub fn add_verb_middleware(&self, verb: &str, middleware: impl Middleware) {
+        self.verb_middleware
+            .entry(verb.into())
+            .or_default()
+            .push(Arc::new(middleware));
+    }
+
+    /// Declares a topic that clients can subscribe to with `Client::subscribe`.
+    pub fn add_topic(&self, topic: &str) {
+        self.topics.entry(topic.into()).or_default();
+    }
+
+    /// Publishes a message to every current subscriber of the topic. Subscribers that can't keep up miss messages rather than holding up everybody else.
+    pub fn publish<T: Serialize>(&self, topic: &str, msg: &T) {
+        if let Some(mut subscribers) = self.topics.get_mut(topic) {
+            let msg = stdcode::serialize(msg).unwrap();
+            subscribers.retain(|send| match send.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(smol::channel::TrySendError::Full(_)) => {
+                    log::debug!("dropping message on {:?} for a slow subscriber", topic);
+                    true
+                }
+                Err(smol::channel::TrySendError::Closed(_)) => false,
+            });
+        }
+    }
+
+    /// Broadcasts a message to every node in the network that listens on the topic with `NetState::on_broadcast`. The message spreads by gossip, so delivery is best-effort.
+    pub fn broadcast<T: Serialize>(&self, topic: &str, msg: &T) {
+        let msg = GossipMsg {
+            id: rand::random(),
+            topic: topic.into(),
+            ttl: self.gossip_config.ttl,
+            body: stdcode::serialize(msg).unwrap(),
+        };
+        self.gossip.see(msg.id, self.gossip_config.seen_capacity);
+        self.gossip_forward(msg);
+    }
+
+    /// Registers a handler for broadcasts on the topic. Each message is handled at most once, and is only passed on to other nodes if the handler returns true. Messages that fail to decode are dropped.
+    pub fn on_broadcast<T: DeserializeOwned>(
+        &self,
+        topic: &str,
+        handler: impl Fn(T) -> bool + Send + Sync + 'static,
+    ) {
+        self.gossip.handlers.insert(
+            topic.into(),
+            Arc::new(move |bts: &[u8]| match stdcode::deserialize(bts) {
+                Ok(msg) => handler(msg),
+                Err(err) => {
+                    log::debug!("dropping undecodable broadcast: {}", err);
+                    false
+                }
+            }),
+        );
+    }
+
+    /// Sets how broadcasts spread through the network.
+    pub fn set_gossip_config(&mut self, config: GossipConfig) {
+        self.gossip_config = config;
+    }
+
+    /// Sends a broadcast message to a random selection of neighbors.
+    fn gossip_forward(&self, msg: GossipMsg) {
+        for neigh in self.routes().into_iter().take(self.gossip_config.fanout) {
+            let client = self.client.clone();
+            let network_name = self.network_name.clone();
+            let msg = msg.clone();
+            smolscale::spawn(async move {
+                let res = client
+                    .request_with::<GossipMsg, ()>(
+                        neigh,
+                        &network_name,
+                        GOSSIP_VERB,
+                        msg,
+                        RequestOptions::default().timeout(Duration::from_secs(10)),
+                    )
+                    .await
+                    .map_err(anyhow::Error::from);
+                if let Err(err) = res {
+                    log::debug!("could not gossip to {}: {:?}", neigh, err)
+                }
+            })
+            .detach();
+        }
+    }
+
+    /// Adds a route to the routing table, under the identity of the peer at that address.
     pub fn add_route(&self, addr: SocketAddr) {
-        self.routes.write().add_route(addr)
+        let id = self.peer_id(addr);
+        if let (PeerId::Key(key), Some(kademlia)) = (id, &self.kademlia) {
+            kademlia.write().insert(key, addr);
+        }
+        self.update_routes(|routes| routes.add_route(id, addr))
+    }
+
+    /// Enables a Kademlia routing table alongside the normal one, keeping at most k peers in each bucket. Only peers that prove a static key end up in it, so this is only useful together with `NetState::set_static_key`. This also makes the server answer `find_node` requests.
+    pub fn enable_kademlia(&mut self, k: usize) {
+        self.kademlia = Some(Arc::new(RwLock::new(KBuckets::new(
+            self.static_key.public(),
+            k,
+        ))));
+    }
+
+    /// Returns up to k peers in the Kademlia table closest to the target, closest first.
+    pub fn closest_peers(&self, target: PublicKey) -> Vec<(PublicKey, SocketAddr)> {
+        match &self.kademlia {
+            Some(kademlia) => {
+                let kademlia = kademlia.read();
+                kademlia.closest(&target, kademlia.k())
+            }
+            None => vec![],
+        }
+    }
+
+    /// Iteratively looks up the peers closest to the target through `find_node` requests, returning up to k of them, closest first. Every returned peer answered a request while proving its key, and was added to the routing table. Returns nothing if Kademlia isn't enabled.
+    pub async fn find_node(&self, target: PublicKey) -> Vec<(PublicKey, SocketAddr)> {
+        let kademlia = match &self.kademlia {
+            Some(kademlia) => kademlia.clone(),
+            None => return vec![],
+        };
+        let k = kademlia.read().k();
+        let mut shortlist: BTreeMap<[u8; 32], (PublicKey, SocketAddr)> = kademlia
+            .read()
+            .closest(&target, k)
+            .into_iter()
+            .map(|(key, addr)| (distance(&key, &target), (key, addr)))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = HashSet::new();
+        queried.insert(self.public_key());
+        loop {
+            // ask the closest few we haven't asked yet, until we've asked all of the k closest
+            let batch: Vec<(PublicKey, SocketAddr)> = shortlist
+                .values()
+                .take(k)
+                .filter(|(key, _)| !queried.contains(key))
+                .take(LOOKUP_PARALLELISM)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let queries = batch.into_iter().map(|(key, addr)| {
+                queried.insert(key);
+                async move {
+                    let res = self
+                        .client
+                        .request_with::<PublicKey, Vec<(PublicKey, SocketAddr)>>(
+                            addr,
+                            &self.network_name,
+                            FIND_NODE_VERB,
+                            target,
+                            RequestOptions::default().timeout(Duration::from_secs(10)),
+                        )
+                        .await
+                        .map_err(anyhow::Error::from);
+                    (key, addr, res)
+                }
+            });
+            for (key, addr, res) in futures_util::future::join_all(queries).await {
+                match res {
+                    Ok(found) if self.client.remote_key(addr) == Some(key) => {
+                        self.add_route(addr);
+                        responded.insert(key);
+                        for (found_key, found_addr) in found {
+                            if !queried.contains(&found_key) {
+                                shortlist
+                                    .entry(distance(&found_key, &target))
+                                    .or_insert((found_key, found_addr));
+                            }
+                        }
+                    }
+                    res => {
+                        log::debug!("find_node to {} at {} failed: {:?}", key, addr, res.err());
+                        kademlia.write().remove(&key);
+                        shortlist.remove(&distance(&key, &target));
+                    }
+                }
+            }
+        }
+        shortlist
+            .into_values()
+            .filter(|(key, _)| responded.contains(key))
+            .take(k)
+            .collect()
+    }
+
+    /// Gets the identity of the peer at the given address. This is the static key it most recently proved during an encrypted handshake, falling back to the address itself for peers that never did.
+    pub fn peer_id(&self, addr: SocketAddr) -> PeerId {
+        self.client
+            .remote_key(addr)
+            .map(PeerId::Key)
+            .or_else(|| self.routes.read().peer_at(addr))
+            .unwrap_or(PeerId::Addr(addr))
+    }
+
+    /// Gets every address the peer has been seen at, most recent first.
+    pub fn peer_addrs(&self, id: PeerId) -> Vec<SocketAddr> {
+        self.routes.read().peer_addrs(id)
+    }
+
+    /// Raises a peer's reputation, for example because it sent something useful. Reputations decay towards zero over time.
+    pub fn reward(&self, peer: PeerId, amount: f64) {
+        self.reputation_change(peer, amount);
+    }
+
+    /// Lowers a peer's reputation, for example because it sent an invalid block or transaction. Peers whose reputation falls too low for the reputation policy (below -5 by default) are left out of `NetState::routes` and aren't routed to again until it recovers.
+    pub fn penalize(&self, peer: PeerId, amount: f64) {
+        self.reputation_change(peer, -amount);
+    }
+
+    /// Sets how reputations change, decay, and decide which peers we route to. The default is a `DefaultReputationPolicy`.
+    pub fn set_reputation_policy(&mut self, policy: impl ReputationPolicy) {
+        self.reputation_policy = Arc::new(policy);
+    }
+
+    /// Gets a peer's current reputation, which is zero for peers we know nothing about.
+    pub fn reputation(&self, peer: PeerId) -> f64 {
+        self.reputations
+            .get(&peer)
+            .map(|r| r.get_reputation(&*self.reputation_policy))
+            .unwrap_or_default()
+    }
+
+    /// Gets the current reputation of every peer we have an opinion about.
+    pub fn reputations(&self) -> Vec<(PeerId, f64)> {
+        self.reputations
+            .iter()
+            .map(|entry| {
+                let reputation = entry.value().get_reputation(&*self.reputation_policy);
+                (*entry.key(), reputation)
+            })
+            .collect()
+    }
+
+    /// Gets the current reputation of the peer at the given address.
+    fn reputation_of(&self, addr: SocketAddr) -> f64 {
+        self.reputation(self.peer_id(addr))
+    }
+
+    /// Changes the reputation of the peer at the given address as the reputation policy says it should for the event.
+    fn reputation_event(&self, addr: SocketAddr, event: ReputationEvent) {
+        let delta = self.reputation_policy.delta(event);
+        self.reputation_change(self.peer_id(addr), delta)
+    }
+
+    /// Changes a peer's reputation.
+    fn reputation_change(&self, peer: PeerId, delta: f64) {
+        self.reputations
+            .entry(peer)
+            .or_default()
+            .delta(&*self.reputation_policy, delta)
     }
 
     /// Gets route age.
@@ -347,18 +1381,63 @@ impl NetState {
         self.routes.read().get_route_age(addr)
     }
 
-    /// Obtains a vector of routes. This is guaranteed to be uniformly shuffled, so taking the first N elements is always fair.
+    /// Obtains a vector of routes, with one address for each peer. This is randomly shuffled, but spread over subnets, so that the first N elements come from as many different subnets as possible.
     pub fn routes(&self) -> Vec<SocketAddr> {
-        let mut rr: Vec<SocketAddr> = self.routes.read().to_vec().iter().map(|v| v.0).collect();
-        rr.retain(|v| {
-            if let Some(v) = self.reputations.get(v) {
-                v.value().get_reputation() > -5.0
-            } else {
-                true
-            }
+        let mut rr = self.routes.read().peers();
+        rr.retain(|(id, addr)| {
+            !self.bans.is_banned(addr.ip())
+                && self.reputation_policy.is_routable(self.reputation(*id))
         });
-        rr.shuffle(&mut thread_rng());
-        rr
+        rr.into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    /// Sets the static key that identifies this node in encrypted handshakes. This also makes the node encrypt all its own connections to other nodes. Without a static key, a node accepts encrypted connections using a random key, but talks to others in plaintext.
+    ///
+    /// This only changes this `NetState` and clones made from it afterwards, so call it before registering verbs with `NetState::listen` and before `NetState::start_server`. Handlers registered earlier, and a server that's already running, keep the old key.
+    pub fn set_static_key(&mut self, key: NodeKey) {
+        self.warn_if_in_use("set_static_key");
+        self.client = Arc::new(
+            self.client
+                .reconfigure(self.client.config().clone().static_key(key.clone())),
+        );
+        if let Some(kademlia) = &self.kademlia {
+            let k = kademlia.read().k();
+            self.kademlia = Some(Arc::new(RwLock::new(KBuckets::new(key.public(), k))));
+        }
+        self.static_key = key;
+    }
+
+    /// Sets the connector used for this node's own connections to other nodes, such as when gossiping routes. The default is plain TCP. Like `NetState::set_static_key`, this has to be called before registering verbs or starting the server.
+    pub fn set_connector(&mut self, connector: impl Connector) {
+        self.warn_if_in_use("set_connector");
+        self.client = Arc::new(
+            self.client
+                .reconfigure(self.client.config().clone().connector(connector)),
+        );
+    }
+
+    /// Pins the node at the given address to a static public key, as `Client::pin` does, for this node's own connections to it. Pins are shared by every clone of the `NetState`, including ones already handling requests.
+    pub fn pin(&self, addr: SocketAddr, key: PublicKey) {
+        self.client.pin(addr, key);
+    }
+
+    fn warn_if_in_use(&self, setter: &str) {
+        if self.server.lock().is_some() || !self.verbs.is_empty() {
+            log::warn!(
+                "{} called after registering verbs or starting the server, which keep the old setting",
+                setter
+            );
+        }
+    }
+
+    /// Sets whether to refuse plaintext connections.
+    pub fn set_require_encryption(&mut self, require: bool) {
+        self.require_encryption = require;
+    }
+
+    /// Returns the static public key of this node.
+    pub fn public_key(&self) -> PublicKey {
+        self.static_key.public()
     }
 
     /// Sets the name of the network state.
@@ -373,3 +1452,38 @@ impl NetState {
         ns
     }
 }
+
+/// One connection to the server, as seen by whatever is responding to requests on it.
+#[derive(Clone)]
+struct ServerConn {
+    peer_addr: SocketAddr,
+    remote_key: Option<PublicKey>,
+    writer: Arc<smol::lock::Mutex<WriteHalf<BoxedConnection>>>,
+    codec: FrameCodec,
+    shutdown: Arc<Shutdown>,
+    // requests being handled in the background, each with a way to cancel it
+    in_flight: Arc<DashMap<u64, smol::channel::Sender<()>>>,
+}
+
+impl ServerConn {
+    /// Writes a whole frame back to the client.
+    async fn write(&self, frame: &[u8]) -> anyhow::Result<()> {
+        self.codec
+            .write(&mut *self.writer.lock().await, frame)
+            .await?;
+        Ok(())
+    }
+
+    /// Describes where requests on this connection come from.
+    fn meta(&self, proto_ver: u8, received: SystemTime, deadline: Option<Instant>) -> RequestMeta {
+        RequestMeta {
+            remote_addr: self.peer_addr,
+            remote_key: self.remote_key,
+            proto_ver,
+            received,
+            deadline,
+        }
+    }
+}
+
+type EvictionObserver = Arc<dyn Fn(&Eviction) + Send + Sync>;
@@ -0,0 +1,280 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::framing::{FrameBudget, FrameLimits};
+use crate::{PeerId, MAX_MSG_SIZE};
+
+/// Limits on how much of a server any one peer, and everybody together, can use.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerLimits {
+    /// The most connections the server keeps open at once.
+    pub max_connections: usize,
+    /// The most connections the server keeps open to any one IP address, counting each IPv6 /64 as one address.
+    pub max_connections_per_ip: usize,
+    /// How many requests per second each IP address may make in the long run.
+    pub requests_per_second: f64,
+    /// How many requests each IP address may make in a burst, on top of the long-run rate.
+    pub request_burst: f64,
+    /// The largest request frame the server reads, unless a verb allows larger ones.
+    pub max_frame_size: usize,
+    /// How long the rest of a request frame may take to arrive once it has started.
+    pub frame_timeout: Duration,
+    /// How much memory all request frames being read at once may take up together.
+    pub max_frame_memory: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_connections_per_ip: 32,
+            requests_per_second: 100.0,
+            request_burst: 200.0,
+            max_frame_size: MAX_MSG_SIZE as usize,
+            frame_timeout: Duration::from_secs(30),
+            max_frame_memory: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Enforces [ServerLimits] across all of a server's connections.
+pub(crate) struct Limiter {
+    limits: ServerLimits,
+    total: AtomicUsize,
+    per_ip: DashMap<IpAddr, PeerUsage>,
+    frame_budget: Arc<FrameBudget>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(ServerLimits::default())
+    }
+}
+
+impl Limiter {
+    pub fn new(limits: ServerLimits) -> Self {
+        Self {
+            limits,
+            total: AtomicUsize::new(0),
+            per_ip: DashMap::new(),
+            frame_budget: Arc::new(FrameBudget::new(limits.max_frame_memory)),
+        }
+    }
+
+    /// The limits for reading one request frame of at most the given size.
+    pub fn frame_limits(&self, max_size: usize) -> FrameLimits {
+        FrameLimits {
+            max_size,
+            deadline: Some(self.limits.frame_timeout),
+            budget: Some(self.frame_budget.clone()),
+        }
+    }
+
+    /// The largest request frame the server reads by default.
+    pub fn max_frame_size(&self) -> usize {
+        self.limits.max_frame_size
+    }
+
+    /// Admits a new connection from the given IP, unless that would exceed a limit. The connection counts against the limits until the guard is dropped.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<ConnGuard> {
+        let ip = limit_key(ip);
+        if self.per_ip.len() > self.limits.max_connections * 2 {
+            // forget idle peers whose buckets have refilled, since they're indistinguishable from new ones
+            let limits = self.limits;
+            self.per_ip
+                .retain(|_, usage| usage.conns > 0 || !usage.bucket.is_full(&limits));
+        }
+        if self.total.fetch_add(1, Ordering::SeqCst) >= self.limits.max_connections {
+            self.total.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let mut usage = self.per_ip.entry(ip).or_insert_with(|| PeerUsage {
+            conns: 0,
+            bucket: TokenBucket::new(&self.limits),
+        });
+        if usage.conns >= self.limits.max_connections_per_ip {
+            drop(usage);
+            self.total.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        usage.conns += 1;
+        Some(ConnGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Takes a token from the IP's bucket, returning whether there was one.
+    pub fn allow_request(&self, ip: IpAddr) -> bool {
+        match self.per_ip.get_mut(&limit_key(ip)) {
+            Some(mut usage) => usage.bucket.take(&self.limits),
+            None => true,
+        }
+    }
+}
+
+/// The address that a peer's usage is counted under. An IPv6 peer usually controls a whole /64, so it counts as one peer no matter which address in it it uses, and IPv4-mapped addresses count as the IPv4 addresses they map.
+fn limit_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+/// The identity shared by everyone who connects from the same IP address, again counting each IPv6 /64 as one address, without proving a static key. It has port 0, which no route has, so it never stands for a route.
+pub(crate) fn ip_peer(ip: IpAddr) -> PeerId {
+    PeerId::Addr(SocketAddr::new(limit_key(ip), 0))
+}
+
+/// Keeps a connection counted against the limits.
+pub(crate) struct ConnGuard {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.limiter.total.fetch_sub(1, Ordering::SeqCst);
+        if let Some(mut usage) = self.limiter.per_ip.get_mut(&self.ip) {
+            usage.conns -= 1;
+        }
+    }
+}
+
+struct PeerUsage {
+    conns: usize,
+    bucket: TokenBucket,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limits: &ServerLimits) -> Self {
+        Self {
+            tokens: limits.request_burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limits: &ServerLimits) {
+        let now = Instant::now();
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limits.requests_per_second).min(limits.request_burst);
+        self.last_refill = now;
+    }
+
+    fn take(&mut self, limits: &ServerLimits) -> bool {
+        self.refill(limits);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, limits: &ServerLimits) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens + elapsed * limits.requests_per_second >= limits.request_burst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ServerLimits {
+        ServerLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            requests_per_second: 1.0,
+            request_burst: 5.0,
+            ..ServerLimits::default()
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bucket_allows_a_burst() {
+        let limits = limits();
+        let mut bucket = TokenBucket::new(&limits);
+        assert!(bucket.is_full(&limits));
+        for _ in 0..5 {
+            assert!(bucket.take(&limits));
+        }
+        assert!(!bucket.take(&limits));
+        assert!(!bucket.is_full(&limits));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limits = limits();
+        let mut bucket = TokenBucket::new(&limits);
+        while bucket.take(&limits) {}
+        // pretend 3 seconds passed, enough for 3 tokens
+        bucket.last_refill -= Duration::from_secs(3);
+        for _ in 0..3 {
+            assert!(bucket.take(&limits));
+        }
+        assert!(!bucket.take(&limits));
+        // refilling stops at the burst size
+        bucket.last_refill -= Duration::from_secs(60);
+        assert!(bucket.is_full(&limits));
+        for _ in 0..5 {
+            assert!(bucket.take(&limits));
+        }
+        assert!(!bucket.take(&limits));
+    }
+
+    #[test]
+    fn admits_within_limits() {
+        let limiter = Arc::new(Limiter::new(limits()));
+        let a = limiter.admit(ip("10.0.0.1")).unwrap();
+        let _b = limiter.admit(ip("10.0.0.1")).unwrap();
+        assert!(limiter.admit(ip("10.0.0.1")).is_none());
+        let _c = limiter.admit(ip("10.0.0.2")).unwrap();
+        assert!(limiter.admit(ip("10.0.0.3")).is_none());
+        drop(a);
+        assert!(limiter.admit(ip("10.0.0.3")).is_some());
+    }
+
+    #[test]
+    fn groups_peers() {
+        assert_eq!(limit_key(ip("10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(limit_key(ip("::ffff:10.0.0.1")), ip("10.0.0.1"));
+        assert_eq!(limit_key(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+
+        let limiter = Arc::new(Limiter::new(limits()));
+        let _a = limiter.admit(ip("2001:db8::1")).unwrap();
+        let _b = limiter.admit(ip("2001:db8::2")).unwrap();
+        assert!(limiter.admit(ip("2001:db8::3")).is_none());
+        assert!(limiter.admit(ip("2001:db8:0:1::1")).is_some());
+    }
+
+    #[test]
+    fn rate_limits_connected_peers() {
+        let limiter = Arc::new(Limiter::new(limits()));
+        let _guard = limiter.admit(ip("10.0.0.1")).unwrap();
+        for _ in 0..5 {
+            assert!(limiter.allow_request(ip("10.0.0.1")));
+        }
+        assert!(!limiter.allow_request(ip("10.0.0.1")));
+        assert!(limiter.allow_request(ip("10.0.0.2")));
+    }
+}
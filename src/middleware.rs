@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use smol::prelude::*;
+
+use crate::endpoint::BoxedResponder;
+use crate::{ErrorCode, RemoteError, RequestMeta};
+
+/// Middleware runs around verb handlers, for things like logging, metrics, access control and timeouts. It can look at a call, change its body, refuse it by returning an error without calling `next`, or pass it on with `Next::run` and look at the response.
+#[async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    /// Handles a call, usually by passing it on to the rest of the stack.
+    async fn call(&self, call: Call, next: Next) -> Result<Vec<u8>, RemoteError>;
+}
+
+#[async_trait]
+impl<
+        F: Fn(Call, Next) -> R + 'static + Send + Sync,
+        R: Future<Output = Result<Vec<u8>, RemoteError>> + Send + 'static,
+    > Middleware for F
+{
+    async fn call(&self, call: Call, next: Next) -> Result<Vec<u8>, RemoteError> {
+        (self)(call, next).await
+    }
+}
+
+/// A request on its way to a verb handler, as middleware sees it.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// The verb being called. The handler is picked before any middleware runs, so changing this doesn't reroute the call.
+    pub verb: String,
+    /// The request ID, or `None` for requests over the legacy protocol, which don't have one.
+    pub id: Option<u64>,
+    /// The stdcode-encoded request body.
+    pub body: Vec<u8>,
+    /// Where and when the request came from.
+    pub meta: RequestMeta,
+}
+
+/// The rest of a middleware stack, ending with the verb handler.
+pub struct Next {
+    stack: Arc<Vec<Arc<dyn Middleware>>>,
+    idx: usize,
+    responder: Option<BoxedResponder>,
+}
+
+impl Next {
+    pub(crate) fn new(stack: Vec<Arc<dyn Middleware>>, responder: Option<BoxedResponder>) -> Self {
+        Self {
+            stack: Arc::new(stack),
+            idx: 0,
+            responder,
+        }
+    }
+
+    /// Passes the call on to the next middleware, or to the verb handler once there are no more.
+    pub async fn run(mut self, call: Call) -> Result<Vec<u8>, RemoteError> {
+        match self.stack.get(self.idx).cloned() {
+            Some(middleware) => {
+                self.idx += 1;
+                middleware.call(call, self).await
+            }
+            None => match &self.responder {
+                Some(responder) => responder.0(&call.body, call.meta).await,
+                None => Err(RemoteError::new(ErrorCode::NoVerb, "verb not found")),
+            },
+        }
+    }
+}
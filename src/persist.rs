@@ -0,0 +1,61 @@
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::PeerId;
+
+/// Everything a `NetState` remembers about other nodes across restarts.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Known routes: which peer was seen at which address, and when.
+    pub routes: Vec<(PeerId, SocketAddr, SystemTime)>,
+    /// Raw reputation scores, along with when each was last updated. Scores decay from that time on.
+    pub reputations: Vec<(PeerId, f64, SystemTime)>,
+}
+
+/// A Persistence backend saves and loads `NetState` snapshots. Its methods may block, and are always called off the async executor.
+pub trait Persistence: Send + Sync + 'static {
+    /// Loads the last saved snapshot, if there is one.
+    fn load(&self) -> std::io::Result<Option<Snapshot>>;
+
+    /// Saves a snapshot, replacing the previous one.
+    fn save(&self, snapshot: &Snapshot) -> std::io::Result<()>;
+}
+
+/// Persists snapshots to a single file.
+#[derive(Clone, Debug)]
+pub struct FilePersistence {
+    path: PathBuf,
+}
+
+impl FilePersistence {
+    /// Persists to the file at the given path. The file is created on the first save.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Persistence for FilePersistence {
+    fn load(&self) -> std::io::Result<Option<Snapshot>> {
+        match std::fs::read(&self.path) {
+            Ok(bts) => stdcode::deserialize(&bts)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> std::io::Result<()> {
+        // write to a temporary file first, so that a crash never leaves a half-written snapshot behind
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = std::fs::File::create(&tmp_path)?;
+        tmp.write_all(&stdcode::serialize(snapshot).unwrap())?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)
+    }
+}
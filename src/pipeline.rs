@@ -1,63 +1,259 @@
-use std::{convert::Infallible};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
-use async_net::TcpStream;
 use concurrent_queue::ConcurrentQueue;
 use futures_util::{future::Shared, Future, FutureExt};
+use parking_lot::Mutex;
 use smol::prelude::*;
 use smol::{
     channel::{Receiver, Sender},
     Task,
 };
 
-use crate::{read_len_bts, write_len_bts, MelnetError};
+use crate::crypt::FrameCodec;
+use crate::framing::FrameLimits;
+use crate::reqs::{LegacyRawRequest, LegacyRawResponse, RawRequest, RawResponse};
+use crate::transport::BoxedConnection;
+use crate::{read_len_bts, write_len_bts, MelnetError, CANCEL_VERB, PROTO_VER};
 
-/// A fully pipelined TCP req/resp connection.
+/// A fully pipelined req/resp connection.
 #[derive(Clone)]
 pub struct Pipeline {
-    send_req: Sender<(Vec<u8>, Sender<Vec<u8>>)>,
+    // legacy pipelines match responses by order, so the response sender comes along with the request
+    send_req: Sender<(RawRequest, Option<Sender<RawResponse>>)>,
     recv_err: Shared<Task<Result<Infallible, MelnetError>>>,
+    responded: Arc<AtomicBool>,
+    // whether the other side answered in the legacy protocol instead
+    legacy_reply: Arc<AtomicBool>,
+    // who's waiting for responses to which request IDs; always empty for legacy pipelines
+    waiting: Arc<Mutex<HashMap<u64, Waiter>>>,
+    next_id: Arc<AtomicU64>,
+    legacy: bool,
+}
+
+/// Cancels a request if it's dropped before the response arrives.
+struct CancelOnDrop<'a> {
+    pipeline: &'a Pipeline,
+    id: u64,
+    netname: String,
+}
+
+impl<'a> Drop for CancelOnDrop<'a> {
+    fn drop(&mut self) {
+        self.pipeline
+            .cancel(self.id, std::mem::take(&mut self.netname));
+    }
+}
+
+/// Something waiting for responses on a pipeline.
+enum Waiter {
+    /// A normal request, which gets exactly one response.
+    Once(Sender<RawResponse>),
+    /// A subscription, which gets responses until it's cancelled.
+    Stream(Sender<RawResponse>),
 }
 
 impl Pipeline {
-    /// Wraps a Pipeline around the given TCP stream
-    pub fn new(stream: TcpStream) -> Self {
+    /// Wraps a Pipeline around the given connection, speaking the current protocol version through the given codec. Responses are matched to requests by ID.
+    pub fn new(stream: BoxedConnection, codec: FrameCodec) -> Self {
         let (send_req, recv_req) = smol::channel::bounded(16);
-        let task = smolscale::spawn(pipeline_inner(stream, recv_req));
+        let responded = Arc::new(AtomicBool::new(false));
+        let legacy_reply = Arc::new(AtomicBool::new(false));
+        let waiting = Arc::new(Mutex::new(HashMap::new()));
+        let task = smolscale::spawn(pipeline_inner(
+            stream,
+            codec,
+            recv_req,
+            waiting.clone(),
+            responded.clone(),
+            legacy_reply.clone(),
+        ));
         Self {
             send_req,
             recv_err: task.shared(),
+            responded,
+            legacy_reply,
+            waiting,
+            next_id: Default::default(),
+            legacy: false,
         }
     }
 
-    /// Does a single request onto the pipeline.
-    pub async fn request(&self, req: Vec<u8>) -> Result<Vec<u8>, MelnetError> {
+    /// Wraps a Pipeline around the given connection, speaking the legacy, strictly in-order, plaintext protocol.
+    pub fn new_legacy(stream: BoxedConnection) -> Self {
+        let (send_req, recv_req) = smol::channel::bounded(16);
+        let responded = Arc::new(AtomicBool::new(false));
+        let task = smolscale::spawn(pipeline_legacy(stream, recv_req, responded.clone()));
+        Self {
+            send_req,
+            recv_err: task.shared(),
+            responded,
+            legacy_reply: Default::default(),
+            waiting: Default::default(),
+            next_id: Default::default(),
+            legacy: true,
+        }
+    }
+
+    /// Does a single request onto the pipeline. The request ID is filled in by the pipeline. If the returned future is dropped before the response arrives, the server is told to stop working on the request.
+    pub async fn request(&self, mut req: RawRequest) -> Result<RawResponse, MelnetError> {
         let (send_resp, recv_resp) = smol::channel::bounded(1);
-        let _ = self.send_req.send((req, send_resp)).await;
+        req.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut cancel = None;
+        if self.legacy {
+            let _ = self.send_req.send((req, Some(send_resp))).await;
+        } else {
+            self.waiting.lock().insert(req.id, Waiter::Once(send_resp));
+            cancel = Some(CancelOnDrop {
+                pipeline: self,
+                id: req.id,
+                netname: req.netname.clone(),
+            });
+            let _ = self.send_req.send((req, None)).await;
+        }
         let recv_err = self.recv_err.clone();
-        async { Ok(uob(recv_resp.recv()).await) }
+        let res = async { Ok(uob(recv_resp.recv()).await) }
             .or(async { Err(recv_err.await.unwrap_err()) })
-            .await
+            .await;
+        // once the response is in, dropping the guard does nothing
+        drop(cancel);
+        res
+    }
+
+    /// Tells the server to stop working on a request, and stops waiting for its response. Does nothing if the response already arrived.
+    fn cancel(&self, id: u64, netname: String) {
+        if self.waiting.lock().remove(&id).is_none() || self.send_req.is_closed() {
+            return;
+        }
+        let rr = RawRequest {
+            proto_ver: PROTO_VER,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            netname,
+            verb: CANCEL_VERB.to_owned(),
+            payload: stdcode::serialize(&id).unwrap(),
+            budget: None,
+        };
+        let send_req = self.send_req.clone();
+        smolscale::spawn(async move {
+            let _ = send_req.send((rr, None)).await;
+        })
+        .detach();
+    }
+
+    /// Sends a subscription request onto the pipeline. Every response carrying its ID, starting with the server's acknowledgement, comes out of the returned receiver, until the subscription is cancelled.
+    pub async fn subscribe(
+        &self,
+        mut req: RawRequest,
+    ) -> Result<(u64, Receiver<RawResponse>), MelnetError> {
+        if self.legacy {
+            return Err(MelnetError::VerbNotFound);
+        }
+        let (send_resp, recv_resp) = smol::channel::bounded(64);
+        req.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.waiting
+            .lock()
+            .insert(req.id, Waiter::Stream(send_resp));
+        let id = req.id;
+        let _ = self.send_req.send((req, None)).await;
+        Ok((id, recv_resp))
+    }
+
+    /// Stops routing responses for the given subscription.
+    pub fn cancel_subscription(&self, id: u64) {
+        self.waiting.lock().remove(&id);
+    }
+
+    /// Resolves with the error that killed the pipeline.
+    pub async fn dead(&self) -> MelnetError {
+        self.recv_err.clone().await.unwrap_err()
+    }
+
+    /// Whether the other side has ever sent back a response on this pipeline.
+    pub fn has_responded(&self) -> bool {
+        self.responded.load(Ordering::Relaxed)
+    }
+
+    /// Whether the other side answered in the legacy protocol, which is how servers that predate request IDs complain about requests they can't make sense of. The pipeline dies right after such an answer.
+    pub fn got_legacy_reply(&self) -> bool {
+        self.legacy_reply.load(Ordering::Relaxed)
     }
 }
 
 async fn pipeline_inner(
-    mut ustream: TcpStream,
-    recv_req: Receiver<(Vec<u8>, Sender<Vec<u8>>)>,
+    stream: BoxedConnection,
+    codec: FrameCodec,
+    recv_req: Receiver<(RawRequest, Option<Sender<RawResponse>>)>,
+    waiting: Arc<Mutex<HashMap<u64, Waiter>>>,
+    responded: Arc<AtomicBool>,
+    legacy_reply: Arc<AtomicBool>,
+) -> Result<Infallible, MelnetError> {
+    let (mut dstream, mut ustream) = smol::io::split(stream);
+    let up = async {
+        loop {
+            let (req, _) = uob(recv_req.recv()).await;
+            codec
+                .write(&mut ustream, &stdcode::serialize(&req).unwrap())
+                .await?;
+        }
+    };
+    let down = async {
+        let limits = FrameLimits::default();
+        loop {
+            let (frame, _) = codec.read(&mut dstream, &limits).await?;
+            let resp: RawResponse = stdcode::deserialize(&frame).map_err(|e| {
+                if stdcode::deserialize::<LegacyRawResponse>(&frame).is_ok() {
+                    legacy_reply.store(true, Ordering::Relaxed);
+                }
+                MelnetError::Network(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            })?;
+            responded.store(true, Ordering::Relaxed);
+            let mut waiting = waiting.lock();
+            let id = resp.id;
+            match waiting.remove(&id) {
+                Some(Waiter::Once(send_resp)) => {
+                    let _ = send_resp.try_send(resp);
+                }
+                Some(Waiter::Stream(send_resp)) => {
+                    if let Err(smol::channel::TrySendError::Full(_)) = send_resp.try_send(resp) {
+                        log::debug!("dropping message for a subscription that can't keep up");
+                    }
+                    waiting.insert(id, Waiter::Stream(send_resp));
+                }
+                None => {}
+            }
+        }
+    };
+    up.race(down).await
+}
+
+async fn pipeline_legacy(
+    stream: BoxedConnection,
+    recv_req: Receiver<(RawRequest, Option<Sender<RawResponse>>)>,
+    responded: Arc<AtomicBool>,
 ) -> Result<Infallible, MelnetError> {
     let queue = ConcurrentQueue::unbounded();
-    let mut dstream = ustream.clone();
+    let (mut dstream, mut ustream) = smol::io::split(stream);
     let up = async {
         loop {
             let (req, send_resp) = uob(recv_req.recv()).await;
-            queue.push(send_resp).unwrap();
-            write_len_bts(&mut ustream, &req).await?;
+            queue
+                .push((req.id, send_resp.expect("legacy requests need a sender")))
+                .unwrap();
+            let req: LegacyRawRequest = req.into();
+            write_len_bts(&mut ustream, &stdcode::serialize(&req).unwrap()).await?;
         }
     };
     let down = async {
         loop {
-            let resp = read_len_bts(&mut dstream).await?;
-            if let Ok(send_resp) = queue.pop() {
-                let _ = send_resp.try_send(resp);
+            let resp: LegacyRawResponse = stdcode::deserialize(&read_len_bts(&mut dstream).await?)
+                .map_err(|e| {
+                    MelnetError::Network(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                })?;
+            responded.store(true, Ordering::Relaxed);
+            if let Ok((id, send_resp)) = queue.pop() {
+                let _ = send_resp.try_send(resp.with_id(id));
             }
         }
     };
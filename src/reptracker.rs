@@ -1,9 +1,89 @@
-use std::time::Instant;
+use std::time::{Duration, SystemTime};
+
+/// Something a peer did that changes its reputation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReputationEvent {
+    /// It answered a ping, so its route works.
+    PingAnswered,
+    /// It didn't answer a ping.
+    PingFailed,
+    /// It didn't answer when we asked for its routes.
+    GetRoutesFailed,
+    /// It didn't take an address we told it about.
+    NewAddrFailed,
+    /// It connected while the server was at its connection limits.
+    ConnectionRefused,
+    /// It made requests faster than the server's rate limit.
+    RateLimited,
+}
+
+/// A ReputationPolicy decides how peers' reputations change, how they recover over time, and which peers are too disreputable to route to.
+pub trait ReputationPolicy: Send + Sync + 'static {
+    /// How much an event changes a peer's reputation.
+    fn delta(&self, event: ReputationEvent) -> f64 {
+        match event {
+            ReputationEvent::PingAnswered => 2.0,
+            ReputationEvent::PingFailed => -1.0,
+            ReputationEvent::GetRoutesFailed => -1.0,
+            ReputationEvent::NewAddrFailed => -3.0,
+            ReputationEvent::ConnectionRefused => -1.0,
+            ReputationEvent::RateLimited => -0.1,
+        }
+    }
+
+    /// Applies a change to a peer's current reputation, returning the new one.
+    fn apply(&self, reputation: f64, delta: f64) -> f64;
+
+    /// What a reputation decays to once some time has passed without any changes.
+    fn decay(&self, reputation: f64, elapsed: Duration) -> f64;
+
+    /// Whether we route to a peer with the given reputation.
+    fn is_routable(&self, reputation: f64) -> bool;
+}
+
+/// The default reputation policy: reputations stay within fixed bounds, and decay exponentially towards zero.
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultReputationPolicy {
+    /// The highest reputation a peer can earn, so that a long good record can't outweigh any amount of bad behavior.
+    pub max_reputation: f64,
+    /// The lowest reputation a peer can sink to, so that it can always recover eventually.
+    pub min_reputation: f64,
+    /// How long it takes for a reputation to decay halfway to zero.
+    pub half_life: Duration,
+    /// The lowest reputation we still route to.
+    pub route_threshold: f64,
+}
+
+impl Default for DefaultReputationPolicy {
+    fn default() -> Self {
+        Self {
+            max_reputation: 20.0,
+            min_reputation: -20.0,
+            half_life: Duration::from_secs(3600),
+            route_threshold: -5.0,
+        }
+    }
+}
+
+impl ReputationPolicy for DefaultReputationPolicy {
+    fn apply(&self, reputation: f64, delta: f64) -> f64 {
+        (reputation + delta).clamp(self.min_reputation, self.max_reputation)
+    }
+
+    fn decay(&self, reputation: f64, elapsed: Duration) -> f64 {
+        reputation / 2.0f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64())
+    }
+
+    fn is_routable(&self, reputation: f64) -> bool {
+        reputation >= self.route_threshold
+    }
+}
 
 /// A reputation tracker that automatically takes care of time.
 pub struct RepTracker {
     reputation: f64,
-    last_update: Instant,
+    // wall-clock time, so that reputations keep decaying across restarts
+    last_update: SystemTime,
 }
 
 impl Default for RepTracker {
@@ -17,20 +97,97 @@ impl RepTracker {
     pub fn new() -> Self {
         Self {
             reputation: 0.0,
-            last_update: Instant::now(),
+            last_update: SystemTime::now(),
         }
     }
 
-    /// Updates reputation.
-    pub fn delta(&mut self, rep: f64) {
-        if self.reputation < 20.0 {
-            self.reputation += rep;
-            self.last_update = Instant::now();
+    /// Recreates a reptracker from its raw reputation and the time it was last updated, as returned by `RepTracker::to_parts`.
+    pub fn from_parts(reputation: f64, last_update: SystemTime) -> Self {
+        Self {
+            reputation,
+            last_update,
         }
     }
 
+    /// Returns the raw reputation and the time it was last updated.
+    pub fn to_parts(&self) -> (f64, SystemTime) {
+        (self.reputation, self.last_update)
+    }
+
+    /// Updates reputation, starting from what it has decayed to so far.
+    pub fn delta(&mut self, policy: &dyn ReputationPolicy, rep: f64) {
+        self.reputation = policy.apply(self.get_reputation(policy), rep);
+        self.last_update = SystemTime::now();
+    }
+
     /// Calculate current reputation.
-    pub fn get_reputation(&self) -> f64 {
-        self.reputation / 2.0f64.powf(self.last_update.elapsed().as_secs_f64() / 3600.0)
+    pub fn get_reputation(&self, policy: &dyn ReputationPolicy) -> f64 {
+        let elapsed = self.last_update.elapsed().unwrap_or_default();
+        policy.decay(self.reputation, elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.01
+    }
+
+    #[test]
+    fn penalties_count_at_the_cap() {
+        let policy = DefaultReputationPolicy::default();
+        let mut tracker = RepTracker::new();
+        for _ in 0..20 {
+            tracker.delta(&policy, 2.0);
+        }
+        assert!(close(tracker.get_reputation(&policy), 20.0));
+        tracker.delta(&policy, -3.0);
+        assert!(close(tracker.get_reputation(&policy), 17.0));
+    }
+
+    #[test]
+    fn clamps_to_bounds() {
+        let policy = DefaultReputationPolicy::default();
+        assert_eq!(policy.apply(19.0, 5.0), 20.0);
+        assert_eq!(policy.apply(-19.0, -5.0), -20.0);
+        assert_eq!(policy.apply(1.0, 1.0), 2.0);
+
+        let mut tracker = RepTracker::new();
+        tracker.delta(&policy, -100.0);
+        assert!(close(tracker.get_reputation(&policy), -20.0));
+        tracker.delta(&policy, 1.0);
+        assert!(close(tracker.get_reputation(&policy), -19.0));
+    }
+
+    #[test]
+    fn decays_by_half_each_half_life() {
+        let policy = DefaultReputationPolicy::default();
+        assert_eq!(policy.decay(8.0, Duration::from_secs(0)), 8.0);
+        assert_eq!(policy.decay(8.0, policy.half_life), 4.0);
+        assert_eq!(policy.decay(-8.0, policy.half_life * 2), -2.0);
+
+        let tracker = RepTracker::from_parts(8.0, SystemTime::now() - policy.half_life);
+        assert!(close(tracker.get_reputation(&policy), 4.0));
+        // decay starts from the last update, which moves to now
+        let mut tracker = tracker;
+        tracker.delta(&policy, 1.0);
+        let (reputation, last_update) = tracker.to_parts();
+        assert!(close(reputation, 5.0));
+        assert!(last_update.elapsed().unwrap() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn routes_down_to_the_threshold() {
+        let policy = DefaultReputationPolicy::default();
+        assert!(policy.is_routable(0.0));
+        assert!(policy.is_routable(-5.0));
+        assert!(!policy.is_routable(-5.01));
+        let strict = DefaultReputationPolicy {
+            route_threshold: 0.0,
+            ..policy
+        };
+        assert!(!strict.is_routable(-0.5));
     }
 }
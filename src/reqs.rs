@@ -1,19 +1,86 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
+use crate::{ErrorCode, RemoteError, LEGACY_PROTO_VER};
+
+/// A request, as sent over the wire in protocol version 2. The `id` is chosen by the client and echoed back in the corresponding [RawResponse], so responses can arrive in any order.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RawRequest {
     pub proto_ver: u8,
+    pub id: u64,
     pub netname: String,
     pub verb: String,
     pub payload: Vec<u8>,
+    /// How long the client will wait for a response, counting from when the server reads the request. The server gives up on the request once this runs out.
+    pub budget: Option<Duration>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RawResponse {
+    pub id: u64,
+    pub result: Result<Vec<u8>, RemoteError>,
+}
+
+/// A request in protocol version 1, which has no request ID. Responses come back strictly in order.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LegacyRawRequest {
+    pub proto_ver: u8,
+    pub netname: String,
+    pub verb: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LegacyRawResponse {
     pub kind: String,
     pub body: Vec<u8>,
 }
 
+impl From<RawRequest> for LegacyRawRequest {
+    fn from(req: RawRequest) -> Self {
+        Self {
+            proto_ver: LEGACY_PROTO_VER,
+            netname: req.netname,
+            verb: req.verb,
+            payload: req.payload,
+        }
+    }
+}
+
+impl LegacyRawResponse {
+    /// Downgrades the result of a request to a legacy response, which only knows about a few kinds of errors.
+    pub fn from_result(result: Result<Vec<u8>, RemoteError>) -> Self {
+        match result {
+            Ok(body) => Self {
+                kind: "Ok".into(),
+                body,
+            },
+            Err(err) if err.code == ErrorCode::NoVerb => Self {
+                kind: "NoVerb".into(),
+                body: vec![],
+            },
+            Err(err) => Self {
+                kind: "Err".into(),
+                body: err.message.into_bytes(),
+            },
+        }
+    }
+
+    /// Upgrades a legacy response to a [RawResponse] with the given request ID.
+    pub fn with_id(self, id: u64) -> RawResponse {
+        let result = match self.kind.as_ref() {
+            "Ok" => Ok(self.body),
+            "NoVerb" => Err(RemoteError::new(ErrorCode::NoVerb, "verb not found")),
+            _ => Err(RemoteError::new(
+                ErrorCode::Custom,
+                String::from_utf8_lossy(&self.body),
+            )),
+        };
+        RawResponse { id, result }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RoutingRequest {
     pub proto: String,
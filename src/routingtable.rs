@@ -1,33 +1,521 @@
-use std::net::SocketAddr;
-use std::time::Instant;
-use std::{collections::HashMap, time::Duration};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
 
-#[derive(Debug, Default)]
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::PublicKey;
+
+/// The identity of a peer. Peers that proved a static key during the encrypted handshake are identified by that key, wherever they connect from; all others can only be identified by their address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PeerId {
+    Key(PublicKey),
+    Addr(SocketAddr),
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerId::Key(key) => write!(f, "{}", key),
+            PeerId::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// A group of addresses likely to be controlled by the same party: an IPv4 /16, or an IPv6 /32.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Subnet {
+    V4([u8; 2]),
+    V6([u8; 4]),
+}
+
+/// Which addresses a node is willing to route to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressPolicy {
+    /// Only publicly routable addresses.
+    #[default]
+    Public,
+    /// Publicly routable addresses, plus private and link-local ones, for networks within a LAN.
+    Lan,
+    /// Any unicast address, including loopback ones, for test networks on one machine.
+    Test,
+}
+
+impl AddressPolicy {
+    /// Whether the address is acceptable as a route. Unspecified, multicast and broadcast addresses, and port 0, never are.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        if addr.port() == 0 {
+            return false;
+        }
+        let class = match addr.ip() {
+            IpAddr::V4(ip) => class_v4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => class_v4(ip),
+                None => class_v6(ip),
+            },
+        };
+        match class {
+            AddrClass::Global => true,
+            AddrClass::Private => *self != AddressPolicy::Public,
+            AddrClass::Loopback => *self == AddressPolicy::Test,
+            AddrClass::Invalid => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AddrClass {
+    Global,
+    Private,
+    Loopback,
+    Invalid,
+}
+
+fn class_v4(ip: Ipv4Addr) -> AddrClass {
+    if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation() {
+        AddrClass::Invalid
+    } else if ip.is_loopback() {
+        AddrClass::Loopback
+    } else if ip.is_private() || ip.is_link_local() {
+        AddrClass::Private
+    } else {
+        AddrClass::Global
+    }
+}
+
+fn class_v6(ip: Ipv6Addr) -> AddrClass {
+    let o = ip.octets();
+    if ip.is_unspecified() || ip.is_multicast() {
+        AddrClass::Invalid
+    } else if ip.is_loopback() {
+        AddrClass::Loopback
+    } else if o[0] & 0xfe == 0xfc || (o[0] == 0xfe && o[1] & 0xc0 == 0x80) {
+        // fc00::/7 is unique-local and fe80::/10 is link-local
+        AddrClass::Private
+    } else {
+        AddrClass::Global
+    }
+}
+
+/// The subnet of a publicly routable address. Other addresses, such as loopback and private ones, don't belong to any subnet, and aren't subject to quotas.
+fn subnet_of(addr: &SocketAddr) -> Option<Subnet> {
+    match addr.ip() {
+        IpAddr::V4(ip) => subnet_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => subnet_v4(ip),
+            None => subnet_v6(ip),
+        },
+    }
+}
+
+fn subnet_v4(ip: Ipv4Addr) -> Option<Subnet> {
+    if class_v4(ip) != AddrClass::Global {
+        return None;
+    }
+    let o = ip.octets();
+    Some(Subnet::V4([o[0], o[1]]))
+}
+
+fn subnet_v6(ip: Ipv6Addr) -> Option<Subnet> {
+    if class_v6(ip) != AddrClass::Global {
+        return None;
+    }
+    let o = ip.octets();
+    Some(Subnet::V6([o[0], o[1], o[2], o[3]]))
+}
+
+/// Shuffles addresses so that any prefix of the result covers as many different subnets as possible.
+fn diversify(mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    addrs.shuffle(&mut rand::thread_rng());
+    // the nth address from each subnet sorts before the (n+1)th address of any subnet. Addresses without a subnet count as one more subnet.
+    let mut seen: HashMap<Option<Subnet>, usize> = HashMap::new();
+    let mut ranked: Vec<(usize, SocketAddr)> = addrs
+        .into_iter()
+        .map(|addr| {
+            let rank = seen.entry(subnet_of(&addr)).or_default();
+            *rank += 1;
+            (*rank - 1, addr)
+        })
+        .collect();
+    ranked.sort_by_key(|(rank, _)| *rank);
+    ranked.into_iter().map(|(_, addr)| addr).collect()
+}
+
+/// How long a route lasts without being seen again, and an address we heard about lasts without being reached.
+const ROUTE_EXPIRY: Duration = Duration::from_secs(3600);
+
+fn is_expired(time: SystemTime) -> bool {
+    time.elapsed().unwrap_or_default() >= ROUTE_EXPIRY
+}
+
+/// How the routing table picks which routes to drop once it's full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Drop the routes that were seen longest ago.
+    #[default]
+    Oldest,
+    /// Drop the routes to the peers with the lowest reputation, oldest first among equals.
+    LowestReputation,
+    /// Drop random routes.
+    Random,
+}
+
+/// Why a route was dropped from the routing table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// It wasn't seen for an hour.
+    Expired,
+    /// Its subnet had too many routes.
+    SubnetQuota,
+    /// The table was full.
+    Capacity,
+}
+
+/// A route dropped from the routing table.
+#[derive(Clone, Copy, Debug)]
+pub struct Eviction {
+    pub addr: SocketAddr,
+    /// The peer last seen at the address, for routes that answered us. Addresses we only heard about have none.
+    pub peer: Option<PeerId>,
+    pub reason: EvictionReason,
+}
+
+#[derive(Debug)]
 pub struct RoutingTable {
-    addr_last_seen: HashMap<SocketAddr, Instant>,
+    // "tried" addresses: every address each peer has been reached at, with wall-clock times, so that they still mean something after a restart
+    peer_addrs: HashMap<PeerId, HashMap<SocketAddr, SystemTime>>,
+    // which peer was last seen at each address
+    addr_peer: HashMap<SocketAddr, PeerId>,
+    // "new" addresses: ones we've heard about but haven't reached yet, and when we heard about them
+    new_addrs: HashMap<SocketAddr, SystemTime>,
+
+    // the most addresses from one subnet that each table may hold
+    tried_quota: usize,
+    new_quota: usize,
+
+    // the most addresses each table may hold, and how to pick what goes once they're full
+    tried_capacity: usize,
+    new_capacity: usize,
+    policy: EvictionPolicy,
+
+    // evictions that haven't been reported yet
+    evicted: Vec<Eviction>,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self {
+            peer_addrs: Default::default(),
+            addr_peer: Default::default(),
+            new_addrs: Default::default(),
+            tried_quota: 8,
+            new_quota: 32,
+            tried_capacity: 1024,
+            new_capacity: 4096,
+            policy: EvictionPolicy::default(),
+            evicted: vec![],
+        }
+    }
 }
 
 impl RoutingTable {
-    /// Adds a route to the routing table, asserting that the route is up to date.
-    pub fn add_route(&mut self, addr: SocketAddr) {
-        log::trace!("add route {}", addr);
+    /// Sets the most addresses from one subnet that the tried and new tables may hold.
+    pub fn set_subnet_quotas(&mut self, tried: usize, new: usize) {
+        self.tried_quota = tried.max(1);
+        self.new_quota = new.max(1);
+    }
+
+    /// Sets the most addresses the tried and new tables may hold.
+    pub fn set_capacity(&mut self, tried: usize, new: usize) {
+        self.tried_capacity = tried.max(1);
+        self.new_capacity = new.max(1);
+    }
+
+    /// Sets how to pick which routes to drop once a table is full.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Drops routes until both tables fit within their capacity, using the given reputations if the policy needs them.
+    pub fn enforce_capacity(&mut self, reputation: impl Fn(PeerId) -> f64) {
+        let excess = self.addr_peer.len().saturating_sub(self.tried_capacity);
+        if excess > 0 {
+            let candidates = self
+                .to_vec()
+                .into_iter()
+                .map(|(id, addr, last_seen)| (addr, Some(id), last_seen))
+                .collect();
+            for (addr, id) in self.pick_victims(candidates, excess, &reputation) {
+                if let Some(id) = id {
+                    self.addr_peer.remove(&addr);
+                    self.remove_addr(id, addr);
+                    self.evict(addr, Some(id), EvictionReason::Capacity);
+                }
+            }
+        }
+        let excess = self.new_addrs.len().saturating_sub(self.new_capacity);
+        if excess > 0 {
+            let candidates = self
+                .new_addrs
+                .iter()
+                .map(|(addr, heard)| (*addr, None, *heard))
+                .collect();
+            for (addr, _) in self.pick_victims(candidates, excess, &reputation) {
+                self.new_addrs.remove(&addr);
+                self.evict(addr, None, EvictionReason::Capacity);
+            }
+        }
+    }
+
+    fn pick_victims(
+        &self,
+        mut candidates: Vec<(SocketAddr, Option<PeerId>, SystemTime)>,
+        n: usize,
+        reputation: impl Fn(PeerId) -> f64,
+    ) -> Vec<(SocketAddr, Option<PeerId>)> {
+        match self.policy {
+            EvictionPolicy::Oldest => candidates.sort_unstable_by_key(|(_, _, seen)| *seen),
+            EvictionPolicy::LowestReputation => {
+                let mut ranked: Vec<_> = candidates
+                    .into_iter()
+                    .map(|(addr, id, seen)| {
+                        (reputation(id.unwrap_or(PeerId::Addr(addr))), seen, addr, id)
+                    })
+                    .collect();
+                ranked.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                candidates = ranked
+                    .into_iter()
+                    .map(|(_, seen, addr, id)| (addr, id, seen))
+                    .collect();
+            }
+            EvictionPolicy::Random => candidates.shuffle(&mut rand::thread_rng()),
+        }
+        candidates
+            .into_iter()
+            .take(n)
+            .map(|(addr, id, _)| (addr, id))
+            .collect()
+    }
+
+    fn evict(&mut self, addr: SocketAddr, peer: Option<PeerId>, reason: EvictionReason) {
+        self.evicted.push(Eviction { addr, peer, reason })
+    }
+
+    /// Takes every eviction since the last call.
+    pub fn take_evictions(&mut self) -> Vec<Eviction> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Records an address we've heard about but haven't reached yet. If its subnet already has its quota of new addresses, the one we heard about longest ago makes room.
+    pub fn add_new(&mut self, addr: SocketAddr) {
+        if self.addr_peer.contains_key(&addr) {
+            return;
+        }
+        self.new_addrs.insert(addr, SystemTime::now());
+        if let Some(subnet) = subnet_of(&addr) {
+            let mut same: Vec<(SocketAddr, SystemTime)> = self
+                .new_addrs
+                .iter()
+                .filter(|(other, _)| subnet_of(other) == Some(subnet))
+                .map(|(k, v)| (*k, *v))
+                .collect();
+            if same.len() > self.new_quota {
+                same.sort_unstable_by_key(|(_, heard)| *heard);
+                for (old, _) in &same[..same.len() - self.new_quota] {
+                    self.new_addrs.remove(old);
+                    self.evict(*old, None, EvictionReason::SubnetQuota);
+                }
+            }
+        }
+    }
+
+    /// Forgets an address we heard about but couldn't reach.
+    pub fn remove_new(&mut self, addr: SocketAddr) {
+        self.new_addrs.remove(&addr);
+    }
+
+    /// Picks up to n addresses from the new table to try, spread over as many subnets as possible.
+    pub fn new_candidates(&self, n: usize) -> Vec<SocketAddr> {
+        let mut addrs = diversify(self.new_addrs.keys().copied().collect());
+        addrs.truncate(n);
+        addrs
+    }
+
+    /// Adds a route to the tried table, asserting that the route is up to date.
+    pub fn add_route(&mut self, id: PeerId, addr: SocketAddr) {
         self.clean_up();
-        self.addr_last_seen.insert(addr, Instant::now());
+        self.insert_route(id, addr, SystemTime::now())
+    }
+
+    /// Adds a route that was last seen at the given time, such as one loaded from disk. Routes seen too long ago to still count as tried go into the new table instead, so that they get tried again rather than dropped. Doesn't clean up expired routes, so that loading many routes in a row keeps all of them.
+    pub fn restore_route(&mut self, id: PeerId, addr: SocketAddr, last_seen: SystemTime) {
+        if is_expired(last_seen) {
+            log::trace!("restoring stale route {} at {} as new", id, addr);
+            self.add_new(addr);
+        } else {
+            self.insert_route(id, addr, last_seen);
+        }
+    }
+
+    /// Puts a route in the tried table. Never makes a route look older than it is. If the route's subnet already has its quota of tried addresses, the least recently seen one makes room.
+    fn insert_route(&mut self, id: PeerId, addr: SocketAddr, last_seen: SystemTime) {
+        log::trace!("add route {} at {}", id, addr);
+        self.new_addrs.remove(&addr);
+        // an address belongs to whoever was last seen there
+        if let Some(old) = self.addr_peer.insert(addr, id) {
+            if old != id {
+                self.remove_addr(old, addr);
+            }
+        }
+        let entry = self
+            .peer_addrs
+            .entry(id)
+            .or_default()
+            .entry(addr)
+            .or_insert(last_seen);
+        *entry = (*entry).max(last_seen);
+        if let Some(subnet) = subnet_of(&addr) {
+            let mut same: Vec<(PeerId, SocketAddr, SystemTime)> = self
+                .to_vec()
+                .into_iter()
+                .filter(|(_, other, _)| subnet_of(other) == Some(subnet))
+                .collect();
+            if same.len() > self.tried_quota {
+                same.sort_unstable_by_key(|(_, _, last_seen)| *last_seen);
+                for (old_id, old, _) in &same[..same.len() - self.tried_quota] {
+                    log::debug!("subnet of {} is full, dropping {}", addr, old);
+                    self.addr_peer.remove(old);
+                    self.remove_addr(*old_id, *old);
+                    self.evict(*old, Some(*old_id), EvictionReason::SubnetQuota);
+                }
+            }
+        }
     }
 
     /// Gets the age of a route, if available
     pub fn get_route_age(&self, addr: SocketAddr) -> Option<Duration> {
-        self.addr_last_seen.get(&addr).map(|d| d.elapsed())
+        let id = self.addr_peer.get(&addr)?;
+        self.peer_addrs
+            .get(id)?
+            .get(&addr)
+            .map(|d| d.elapsed().unwrap_or_default())
+    }
+
+    /// Gets the peer last seen at the given address, if any.
+    pub fn peer_at(&self, addr: SocketAddr) -> Option<PeerId> {
+        self.addr_peer.get(&addr).copied()
+    }
+
+    /// Gets every address the peer has been seen at, most recent first.
+    pub fn peer_addrs(&self, id: PeerId) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(SocketAddr, SystemTime)> = self
+            .peer_addrs
+            .get(&id)
+            .map(|addrs| addrs.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default();
+        addrs.sort_unstable_by_key(|(_, last_seen)| std::cmp::Reverse(*last_seen));
+        addrs.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Gets every peer, along with the address it was most recently seen at. The peers are shuffled so that any prefix covers as many different subnets as possible.
+    pub fn peers(&self) -> Vec<(PeerId, SocketAddr)> {
+        let latest: HashMap<SocketAddr, PeerId> = self
+            .peer_addrs
+            .iter()
+            .filter_map(|(id, addrs)| {
+                let (addr, _) = addrs.iter().max_by_key(|(_, last_seen)| **last_seen)?;
+                Some((*addr, *id))
+            })
+            .collect();
+        diversify(latest.keys().copied().collect())
+            .into_iter()
+            .map(|addr| (latest[&addr], addr))
+            .collect()
+    }
+
+    fn remove_addr(&mut self, id: PeerId, addr: SocketAddr) {
+        if let Some(addrs) = self.peer_addrs.get_mut(&id) {
+            addrs.remove(&addr);
+            if addrs.is_empty() {
+                self.peer_addrs.remove(&id);
+            }
+        }
     }
 
     /// Cleans up really old routes.
     fn clean_up(&mut self) {
-        self.addr_last_seen
-            .retain(|_, last_seen| last_seen.elapsed().as_secs() < 3600);
+        let expired: Vec<(PeerId, SocketAddr)> = self
+            .to_vec()
+            .into_iter()
+            .filter(|(_, _, last_seen)| is_expired(*last_seen))
+            .map(|(id, addr, _)| (id, addr))
+            .collect();
+        for (id, addr) in expired {
+            if self.addr_peer.get(&addr) == Some(&id) {
+                self.addr_peer.remove(&addr);
+            }
+            self.remove_addr(id, addr);
+            self.evict(addr, Some(id), EvictionReason::Expired);
+        }
+        let expired: Vec<SocketAddr> = self
+            .new_addrs
+            .iter()
+            .filter(|(_, heard)| is_expired(**heard))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            self.new_addrs.remove(&addr);
+            self.evict(addr, None, EvictionReason::Expired);
+        }
     }
 
     /// Gets all the routes out
-    pub fn to_vec(&self) -> Vec<(SocketAddr, Instant)> {
-        self.addr_last_seen.iter().map(|(k, v)| (*k, *v)).collect()
+    pub fn to_vec(&self) -> Vec<(PeerId, SocketAddr, SystemTime)> {
+        self.peer_addrs
+            .iter()
+            .flat_map(|(id, addrs)| addrs.iter().map(move |(k, v)| (*id, *k, *v)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(host: u8) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, host], 11814))
+    }
+
+    #[test]
+    fn restores_stale_routes_as_new() {
+        let mut table = RoutingTable::default();
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(7200);
+        for host in 1..=5 {
+            table.restore_route(PeerId::Addr(addr(host)), addr(host), two_hours_ago);
+        }
+        assert!(table.to_vec().is_empty());
+        let mut candidates = table.new_candidates(10);
+        candidates.sort();
+        assert_eq!(candidates, (1..=5).map(addr).collect::<Vec<_>>());
+        assert!(table.take_evictions().is_empty());
+
+        // reaching one of them doesn't throw the others away
+        table.add_route(PeerId::Addr(addr(1)), addr(1));
+        assert_eq!(table.peers(), vec![(PeerId::Addr(addr(1)), addr(1))]);
+        assert_eq!(table.new_candidates(10).len(), 4);
+    }
+
+    #[test]
+    fn restores_recent_routes_as_tried() {
+        let mut table = RoutingTable::default();
+        let a_minute_ago = SystemTime::now() - Duration::from_secs(60);
+        for host in 1..=5 {
+            table.restore_route(PeerId::Addr(addr(host)), addr(host), a_minute_ago);
+        }
+        assert_eq!(table.to_vec().len(), 5);
+        assert!(table.new_candidates(10).is_empty());
+        assert_eq!(table.get_route_age(addr(1)).unwrap().as_secs(), 60);
     }
 }
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use event_listener::Event;
+use smol::channel::{Receiver, Sender};
+use smol::Task;
+use smol_timeout::TimeoutExt;
+
+/// Signals shared between a running server and everything it has in flight, so that the server can be shut down gracefully.
+pub(crate) struct Shutdown {
+    // closing these channels wakes up everybody waiting on them
+    draining: (Sender<()>, Receiver<()>),
+    killed: (Sender<()>, Receiver<()>),
+
+    in_flight: AtomicUsize,
+    idle: Event,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            draining: smol::channel::bounded(1),
+            killed: smol::channel::bounded(1),
+            in_flight: AtomicUsize::new(0),
+            idle: Event::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Resolves once the server starts draining. Connections should stop reading new requests at this point.
+    pub async fn draining(&self) {
+        let _ = self.draining.1.recv().await;
+    }
+
+    /// Resolves once the server gives up on draining. Anything still in flight should be dropped at this point.
+    pub async fn killed(&self) {
+        let _ = self.killed.1.recv().await;
+    }
+
+    /// Marks a request as in flight until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    /// Stops accepting new requests, waits up to the deadline for in-flight requests to finish, then drops whatever is left.
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.draining.0.close();
+        let drained = async {
+            loop {
+                let listener = self.idle.listen();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                listener.await;
+            }
+        };
+        if drained.timeout(deadline).await.is_none() {
+            log::warn!(
+                "dropping {} requests still in flight after {:?}",
+                self.in_flight.load(Ordering::SeqCst),
+                deadline
+            );
+        }
+        self.killed.0.close();
+    }
+}
+
+/// Keeps a request counted as in flight.
+pub(crate) struct InFlightGuard(Arc<Shutdown>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify(usize::MAX);
+        }
+    }
+}
+
+/// A server started by `NetState::start_server`.
+pub(crate) struct RunningServer {
+    pub accept_task: Task<()>,
+    pub shutdown: Arc<Shutdown>,
+}
+
+impl RunningServer {
+    /// Stops accepting connections, then drains and shuts down everything in flight.
+    pub async fn stop(self, deadline: Duration) {
+        self.accept_task.cancel().await;
+        self.shutdown.shutdown(deadline).await;
+    }
+}
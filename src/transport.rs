@@ -0,0 +1,173 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_net::{TcpListener, TcpStream};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use smol::channel::{Receiver, Sender};
+use smol::prelude::*;
+
+/// A reliable, ordered, bidirectional byte stream that melnet can run over.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Connection for T {}
+
+/// A type-erased [Connection].
+pub type BoxedConnection = Box<dyn Connection>;
+
+/// A Connector opens connections to melnet servers, identified by `SocketAddr`.
+#[async_trait]
+pub trait Connector: Send + Sync + 'static {
+    /// Opens a connection to the given address.
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<BoxedConnection>;
+}
+
+/// A Listener accepts connections for a melnet server.
+#[async_trait]
+pub trait Listener: Send + Sync + 'static {
+    /// Waits for the next incoming connection, returning it along with the address of the other side.
+    async fn accept(&self) -> std::io::Result<(BoxedConnection, SocketAddr)>;
+
+    /// The address this listener accepts connections on, if it's known. A server never routes to its own address.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Connects over plain TCP. This is the default transport.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpConnector;
+
+#[async_trait]
+impl Connector for TcpConnector {
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<BoxedConnection> {
+        let conn = TcpStream::connect(addr).await?;
+        conn.set_nodelay(true)?;
+        Ok(Box::new(conn))
+    }
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&self) -> std::io::Result<(BoxedConnection, SocketAddr)> {
+        let (conn, addr) = TcpListener::accept(self).await?;
+        conn.set_nodelay(true)?;
+        Ok((Box::new(conn), addr))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpListener::local_addr(self).ok()
+    }
+}
+
+/// An in-memory transport, mostly useful for tests. Servers listen on made-up addresses, and all clones of a `MemTransport` share the same address space. Each connection comes from a different made-up loopback address, so that servers don't lump all their in-memory clients together as one peer.
+#[derive(Clone, Default)]
+pub struct MemTransport {
+    listeners: Arc<DashMap<SocketAddr, Sender<(BoxedConnection, SocketAddr)>>>,
+    next_client: Arc<AtomicU32>,
+}
+
+impl MemTransport {
+    /// Creates a new, empty address space.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listens on the given made-up address, replacing whatever listened there before.
+    pub fn listen(&self, addr: SocketAddr) -> MemListener {
+        let (send, recv) = smol::channel::unbounded();
+        self.listeners.insert(addr, send);
+        MemListener { addr, recv }
+    }
+}
+
+#[async_trait]
+impl Connector for MemTransport {
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<BoxedConnection> {
+        let refused = || std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        let listener = self.listeners.get(&addr).ok_or_else(refused)?.clone();
+        let (client, server) = MemStream::pair();
+        // somewhere in 127.0.0.0/8, skipping the network address
+        let host = self.next_client.fetch_add(1, Ordering::Relaxed) % 0x00ff_ffff + 1;
+        let client_addr = SocketAddr::from((Ipv4Addr::from(0x7f00_0000 | host), 1));
+        listener
+            .send((Box::new(server), client_addr))
+            .await
+            .map_err(|_| refused())?;
+        Ok(Box::new(client))
+    }
+}
+
+/// The listening side of a [MemTransport].
+pub struct MemListener {
+    addr: SocketAddr,
+    recv: Receiver<(BoxedConnection, SocketAddr)>,
+}
+
+#[async_trait]
+impl Listener for MemListener {
+    async fn accept(&self) -> std::io::Result<(BoxedConnection, SocketAddr)> {
+        self.recv
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::NotConnected))
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.addr)
+    }
+}
+
+/// One end of an in-memory duplex byte stream.
+struct MemStream {
+    reader: piper::Reader,
+    writer: piper::Writer,
+}
+
+impl MemStream {
+    fn pair() -> (Self, Self) {
+        let (r1, w1) = piper::pipe(65536);
+        let (r2, w2) = piper::pipe(65536);
+        (
+            Self {
+                reader: r1,
+                writer: w2,
+            },
+            Self {
+                reader: r2,
+                writer: w1,
+            },
+        )
+    }
+}
+
+impl AsyncRead for MemStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use melnet::{MemTransport, NetState, Request};
+use smol::prelude::*;
+
+#[test]
+fn dropped_requests_are_cancelled() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let state = NetState::new_with_name(NETNAME);
+        let (dropped_send, dropped_recv) = smol::channel::unbounded();
+        state.listen("hang", move |_: Request<()>| {
+            let guard = SendOnDrop(dropped_send.clone());
+            async move {
+                let _guard = guard;
+                smol::future::pending::<()>().await;
+                Ok(())
+            }
+        });
+        state.start_server(transport.listen(server_addr()));
+        let client = new_client(&transport);
+
+        let res = client
+            .request::<_, ()>(server_addr(), NETNAME, "hang", ())
+            .or(async {
+                smol::Timer::after(Duration::from_millis(200)).await;
+                Err(melnet::MelnetError::Custom("gave up".into()))
+            })
+            .await;
+        assert!(res.is_err());
+
+        // the client keeps its connection open, so only the cancellation can have dropped the handler
+        let dropped = dropped_recv.recv().or(async {
+            smol::Timer::after(Duration::from_secs(5)).await;
+            Err(smol::channel::RecvError)
+        });
+        assert!(dropped.await.is_ok());
+        drop(client);
+    })
+}
+
+/// Sends a message when dropped, so tests can tell when a handler is.
+struct SendOnDrop(smol::channel::Sender<()>);
+
+impl Drop for SendOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.try_send(());
+    }
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use melnet::{ClientBuilder, MemTransport, RequestOptions};
+
+#[test]
+fn huge_timeouts_mean_no_deadline() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let _state = echo_server(&transport);
+        let client = ClientBuilder::new()
+            .connector(transport.clone())
+            .timeout(Duration::MAX)
+            .build();
+        let res: u64 = client
+            .request(server_addr(), NETNAME, "fast", 1u64)
+            .await
+            .unwrap();
+        assert_eq!(res, 1);
+        let res: u64 = new_client(&transport)
+            .request_with(
+                server_addr(),
+                NETNAME,
+                "fast",
+                2u64,
+                RequestOptions::default().timeout(Duration::MAX),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, 2);
+    })
+}
@@ -0,0 +1,34 @@
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use melnet::{Client, ClientBuilder, MemTransport, NetState, Request};
+
+pub const NETNAME: &str = "test";
+
+pub fn server_addr() -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], 11814))
+}
+
+/// A client over the given transport with a single connection per server, so that concurrent requests share it, and no retries.
+pub fn new_client(transport: &MemTransport) -> Client {
+    ClientBuilder::new()
+        .connector(transport.clone())
+        .pool_size(1)
+        .retry(melnet::RetryPolicy::none())
+        .timeout(Duration::from_secs(10))
+        .build()
+}
+
+/// A server with a `slow` verb that takes half a second and a `fast` verb that doesn't, both echoing their request.
+pub fn echo_server(transport: &MemTransport) -> NetState {
+    let state = NetState::new_with_name(NETNAME);
+    state.listen("slow", |req: Request<u64>| async move {
+        smol::Timer::after(Duration::from_millis(500)).await;
+        Ok(req.body)
+    });
+    state.listen("fast", |req: Request<u64>| async move { Ok(req.body) });
+    state.start_server(transport.listen(server_addr()));
+    state
+}
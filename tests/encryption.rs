@@ -0,0 +1,35 @@
+mod common;
+
+use common::*;
+use melnet::{MemTransport, NetState, NodeKey};
+
+/// A node seeded with the server.
+fn node() -> NetState {
+    let mut state = NetState::new_with_name(NETNAME);
+    state.add_seed(&server_addr().to_string());
+    state
+}
+
+#[test]
+fn nodes_pin_peers() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let mut server = NetState::new_with_name(NETNAME);
+        let server_key = NodeKey::generate();
+        server.set_static_key(server_key.clone());
+        server.start_server(transport.listen(server_addr()));
+
+        let mut honest = node();
+        // pins survive changing the connector
+        honest.pin(server_addr(), server_key.public());
+        honest.set_connector(transport.clone());
+        honest.bootstrap().await;
+        assert_eq!(honest.routes(), vec![server_addr()]);
+
+        let mut fooled = node();
+        fooled.pin(server_addr(), NodeKey::generate().public());
+        fooled.set_connector(transport.clone());
+        fooled.bootstrap().await;
+        assert!(fooled.routes().is_empty());
+    })
+}
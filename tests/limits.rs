@@ -0,0 +1,44 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::*;
+use melnet::{MemTransport, NetState, PeerId, Request, ServerLimits};
+
+#[test]
+fn flooding_clients_lose_reputation() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let mut state = NetState::new_with_name(NETNAME);
+        state.set_server_limits(ServerLimits {
+            requests_per_second: 0.01,
+            request_burst: 3.0,
+            ..ServerLimits::default()
+        });
+        state.listen("fast", |req: Request<u64>| async move { Ok(req.body) });
+        state.start_server(transport.listen(server_addr()));
+
+        let client = new_client(&transport);
+        let observed: SocketAddr = client
+            .request(server_addr(), NETNAME, "observed_addr", ())
+            .await
+            .unwrap();
+        let mut refused = 0;
+        for i in 0..10u64 {
+            if client
+                .request::<_, u64>(server_addr(), NETNAME, "fast", i)
+                .await
+                .is_err()
+            {
+                refused += 1;
+            }
+        }
+        // the client reconnects after being refused, and in-memory connections come from new addresses, so only the first connection's address is sure to have been refused
+        assert!(refused > 0);
+
+        // the client isn't a route, but its IP still loses reputation
+        assert!(state.routes().is_empty());
+        let client_ip = PeerId::Addr(SocketAddr::new(observed.ip(), 0));
+        assert!(state.reputation(client_ip) < 0.0);
+    })
+}
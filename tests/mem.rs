@@ -0,0 +1,67 @@
+mod common;
+
+use std::io::ErrorKind;
+
+use common::*;
+use melnet::{Connector, Listener, MemTransport};
+
+#[test]
+fn requests_work_over_memory() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let _state = echo_server(&transport);
+        let res: u64 = new_client(&transport)
+            .request(server_addr(), NETNAME, "fast", 42u64)
+            .await
+            .unwrap();
+        assert_eq!(res, 42);
+    })
+}
+
+#[test]
+fn connections_come_from_different_addresses() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let listener = transport.listen(server_addr());
+        assert_eq!(listener.local_addr(), Some(server_addr()));
+        let _a = transport.connect(server_addr()).await.unwrap();
+        let _b = transport.connect(server_addr()).await.unwrap();
+        let (_, a) = listener.accept().await.unwrap();
+        let (_, b) = listener.accept().await.unwrap();
+        assert_ne!(a.ip(), b.ip());
+        assert!(a.ip().is_loopback() && b.ip().is_loopback());
+    })
+}
+
+#[test]
+fn nobody_listening_refuses_connections() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let err = transport.connect(server_addr()).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        let listener = transport.listen(server_addr());
+        drop(listener);
+        let err = transport.connect(server_addr()).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    })
+}
+
+#[test]
+fn replaced_listeners_stop_accepting() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let old = transport.listen(server_addr());
+        let _old_state = echo_server(&transport);
+        let err = old.accept().await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+
+        // the old server's listener gets replaced in turn, so it stops accepting instead of falling over
+        let _new_state = echo_server(&transport);
+        let res: u64 = new_client(&transport)
+            .request(server_addr(), NETNAME, "fast", 7u64)
+            .await
+            .unwrap();
+        assert_eq!(res, 7);
+    })
+}
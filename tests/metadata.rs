@@ -0,0 +1,78 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::*;
+use melnet::{ClientBuilder, MemTransport, NetState, NodeKey, PublicKey, Request};
+use serde::{Deserialize, Serialize};
+
+/// What a handler saw of the request's metadata.
+#[derive(Serialize, Deserialize, Debug)]
+struct Seen {
+    remote_addr: SocketAddr,
+    remote_key: Option<PublicKey>,
+    proto_ver: u8,
+    age: Duration,
+    budget: Option<Duration>,
+}
+
+fn meta_server(transport: &MemTransport, key: Option<NodeKey>) -> NetState {
+    let mut state = NetState::new_with_name(NETNAME);
+    if let Some(key) = key {
+        state.set_static_key(key);
+    }
+    state.listen("meta", |req: Request<()>| async move {
+        Ok(Seen {
+            remote_addr: req.meta.remote_addr,
+            remote_key: req.meta.remote_key,
+            proto_ver: req.meta.proto_ver,
+            age: req.meta.received.elapsed().unwrap_or_default(),
+            budget: req.remaining_budget(),
+        })
+    });
+    state.start_server(transport.listen(server_addr()));
+    state
+}
+
+#[test]
+fn handlers_see_who_called() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let _state = meta_server(&transport, None);
+        let client = new_client(&transport);
+        let seen: Seen = client
+            .request(server_addr(), NETNAME, "meta", ())
+            .await
+            .unwrap();
+        // the server tells the client the same address it told the handler
+        let observed: SocketAddr = client
+            .request(server_addr(), NETNAME, "observed_addr", ())
+            .await
+            .unwrap();
+        assert_eq!(seen.remote_addr, observed);
+        assert_eq!(seen.remote_key, None);
+        assert_eq!(seen.proto_ver, 2);
+        assert!(seen.age < Duration::from_secs(5));
+        let budget = seen.budget.unwrap();
+        assert!(budget > Duration::from_secs(5) && budget <= Duration::from_secs(10));
+    })
+}
+
+#[test]
+fn handlers_see_proven_keys() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let _state = meta_server(&transport, Some(NodeKey::generate()));
+        let key = NodeKey::generate();
+        let client = ClientBuilder::new()
+            .connector(transport.clone())
+            .static_key(key.clone())
+            .build();
+        let seen: Seen = client
+            .request(server_addr(), NETNAME, "meta", ())
+            .await
+            .unwrap();
+        assert_eq!(seen.remote_key, Some(key.public()));
+    })
+}
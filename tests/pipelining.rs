@@ -0,0 +1,67 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::*;
+use melnet::{MemTransport, NetState, Request};
+use parking_lot::Mutex;
+
+#[test]
+fn responses_come_back_as_they_finish() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        // records the address each request came from, to check that they all shared one connection
+        let seen = Arc::new(Mutex::new(Vec::<SocketAddr>::new()));
+        let state = NetState::new_with_name(NETNAME);
+        let seen_slow = seen.clone();
+        state.listen("slow", move |req: Request<u64>| {
+            let seen = seen_slow.clone();
+            async move {
+                seen.lock().push(req.meta.remote_addr);
+                smol::Timer::after(Duration::from_millis(500)).await;
+                Ok(req.body)
+            }
+        });
+        let seen_fast = seen.clone();
+        state.listen("fast", move |req: Request<u64>| {
+            let seen = seen_fast.clone();
+            async move {
+                seen.lock().push(req.meta.remote_addr);
+                Ok(req.body)
+            }
+        });
+        state.start_server(transport.listen(server_addr()));
+        let client = new_client(&transport);
+        // open the connection first, so that the other requests share it
+        let res: u64 = client
+            .request(server_addr(), NETNAME, "fast", 0u64)
+            .await
+            .unwrap();
+        assert_eq!(res, 0);
+
+        let finished = Mutex::new(Vec::new());
+        let slow = async {
+            let res: u64 = client
+                .request(server_addr(), NETNAME, "slow", 1u64)
+                .await
+                .unwrap();
+            finished.lock().push(res);
+        };
+        let fast = async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            let res: u64 = client
+                .request(server_addr(), NETNAME, "fast", 2u64)
+                .await
+                .unwrap();
+            finished.lock().push(res);
+        };
+        smol::future::zip(slow, fast).await;
+        assert_eq!(*finished.lock(), vec![2, 1]);
+
+        let seen = seen.lock();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.iter().all(|addr| *addr == seen[0]));
+    })
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use melnet::MemTransport;
+
+#[test]
+fn stop_finishes_requests_in_flight() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let state = echo_server(&transport);
+        let client = new_client(&transport);
+        let in_flight = async {
+            client
+                .request::<_, u64>(server_addr(), NETNAME, "slow", 1u64)
+                .await
+        };
+        let stop = async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            state.stop(Duration::from_secs(5)).await;
+        };
+        let (res, ()) = smol::future::zip(in_flight, stop).await;
+        assert_eq!(res.unwrap(), 1);
+
+        // nothing listens any more, so new connections are refused
+        let res = new_client(&transport)
+            .request::<_, u64>(server_addr(), NETNAME, "fast", 2u64)
+            .await;
+        assert!(res.is_err());
+    })
+}
+
+#[test]
+fn stop_drops_requests_past_the_deadline() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let state = echo_server(&transport);
+        let client = new_client(&transport);
+        let in_flight = async {
+            client
+                .request::<_, u64>(server_addr(), NETNAME, "slow", 1u64)
+                .await
+        };
+        let stop = async {
+            smol::Timer::after(Duration::from_millis(100)).await;
+            state.stop(Duration::from_millis(100)).await;
+        };
+        let (res, ()) = smol::future::zip(in_flight, stop).await;
+        assert!(res.is_err());
+    })
+}
+
+#[test]
+fn servers_restart_after_stopping() {
+    smol::block_on(async {
+        let transport = MemTransport::new();
+        let state = echo_server(&transport);
+        state.stop(Duration::from_secs(0)).await;
+        state.start_server(transport.listen(server_addr()));
+        let res: u64 = new_client(&transport)
+            .request(server_addr(), NETNAME, "fast", 3u64)
+            .await
+            .unwrap();
+        assert_eq!(res, 3);
+    })
+}